@@ -40,6 +40,10 @@ fn get_subcommands() -> Vec<Command> {
             .about("Install apps")
             .args(get_app_args())
             .args(get_channel_args())
+            .args([
+                arg!(--"no-replace" "Install apps again even if they are already there")
+                    .action(clap::ArgAction::SetTrue),
+            ])
             .arg_required_else_help(false),
     ]
 }
@@ -63,11 +67,16 @@ fn get_channel_args() -> Vec<clap::Arg> {
         arg!(-p --port <PORT> "The serial port or device name to use"),
         arg!(--serial "Use the serial bootloader to flash").action(clap::ArgAction::SetTrue),
         // -----
-        arg!(--"flash-file" "Operate on a binary flash file instead of a proper board")
-            .action(clap::ArgAction::SetTrue),
+        arg!(--"flash-file" <FILE> "Operate on a binary flash file instead of a proper board"),
+        arg!(--"flash-address" <ADDRESS> "Flash address of the first byte of the flash file")
+            .value_parser(parse_number)
+            .default_value("0"),
+        arg!(--"flash-size" <SIZE> "Size of the flash file, created or extended with erased flash if needed")
+            .value_parser(parse_number),
         arg!(--board <BOARD> "Explicitly specify the board that is being targeted"),
         arg!(--arch <ARCH> "Explicitly specify the architecture of the board that is being targeted"),
         arg!(--"page-size" <SIZE> "Explicitly specify how many bytes in a flash page")
+            .value_parser(value_parser!(usize))
             .default_value("0"),
         arg!(--"baud-rate" <RATE> "If using serial, set the target baud rate")
             .value_parser(value_parser!(u32))
@@ -81,3 +90,11 @@ fn get_channel_args() -> Vec<clap::Arg> {
         arg!(--tab <TAB> "Specify the path of the tab file"),
     ]
 }
+
+/// Parse a number given either in decimal or in hex with a "0x" prefix.
+fn parse_number(value: &str) -> Result<u64, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
@@ -74,10 +74,7 @@ pub async fn print_info(app_details: &mut [AppAttributes], system_details: &mut
             details.tbf_header.total_size()
         );
 
-        println!(
-            " \x1b[1;32m Address in Flash:  {}",
-            system_details.appaddr.unwrap()
-        );
+        println!(" \x1b[1;32m Address in Flash:  {}", system_details.appaddr);
 
         println!(
             " \x1b[1;32m    TBF version:    {}",
@@ -205,31 +202,40 @@ pub async fn print_info(app_details: &mut [AppAttributes], system_details: &mut
         }
     }
 
+    if !system_details.invalid_attributes.is_empty() {
+        println!("\n\n\x1b[1;33m Invalid Bootloader Attributes");
+        for error in &system_details.invalid_attributes {
+            println!("\x1b[1;33m     {}", error);
+        }
+    }
+
     println!("\n\n\x1b[1;32m Kernel Attributes");
-    println!(
-        "\x1b[1;32m     Sentinel:               {:<10}",
-        system_details.sentinel.clone().unwrap()
-    );
+    let (Some(sentinel), Some(kernel_version)) =
+        (&system_details.sentinel, system_details.kernel_version)
+    else {
+        println!("\x1b[1;32m     No kernel attributes found.\n\n");
+        return;
+    };
+    println!("\x1b[1;32m     Sentinel:               {:<10}", sentinel);
     println!(
         "\x1b[1;32m     Version:                {:<10}",
-        system_details.kernel_version.unwrap()
-    );
-    println!("\x1b[1;32m KATLV: APP Memory");
-    println!(
-        "\x1b[1;32m     app_memory_start:       {:<10}",
-        system_details.app_mem_start.unwrap()
-    );
-    println!(
-        "\x1b[1;32m     app_memory_len:         {:<10}",
-        system_details.app_mem_len.unwrap()
-    );
-    println!("\x1b[1;32m KATLV: Kernel Binary");
-    println!(
-        "\x1b[1;32m     kernel_binary_start:    {:<10}",
-        system_details.kernel_bin_start.unwrap()
-    );
-    println!(
-        "\x1b[1;32m     kernel_binary_len:      {:<10}\n\n",
-        system_details.kernel_bin_len.unwrap()
+        kernel_version
     );
+    if let Some(error) = &system_details.invalid_kernel_attributes {
+        println!("\x1b[1;33m     {}", error);
+    }
+    if let (Some(start), Some(len)) = (system_details.app_mem_start, system_details.app_mem_len) {
+        println!("\x1b[1;32m KATLV: APP Memory");
+        println!("\x1b[1;32m     app_memory_start:       {:<10}", start);
+        println!("\x1b[1;32m     app_memory_len:         {:<10}", len);
+    }
+    if let (Some(start), Some(len)) = (
+        system_details.kernel_bin_start,
+        system_details.kernel_bin_len,
+    ) {
+        println!("\x1b[1;32m KATLV: Kernel Binary");
+        println!("\x1b[1;32m     kernel_binary_start:    {:<10}", start);
+        println!("\x1b[1;32m     kernel_binary_len:      {:<10}", len);
+    }
+    println!("\n");
 }
@@ -0,0 +1,38 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Print the library log messages on the terminal, warnings and errors going to stderr.
+struct TerminalLogger;
+
+impl Log for TerminalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("[{}] {}", record.level(), record.args()),
+            _ => println!("[{}] {}", record.level(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: TerminalLogger = TerminalLogger;
+
+pub fn init(debug: bool) {
+    // The logger can only be set once, which this is the only place doing
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(if debug {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    });
+}
@@ -4,15 +4,19 @@
 
 mod cli;
 mod display;
+mod logger;
 
 use anyhow::{Context, Result};
+use clap::ArgMatches;
 use cli::make_cli;
 use display::{print_info, print_list};
 use inquire::Select;
 use tockloader_lib::{
     connection::{Connection, ConnectionInfo},
+    events::{InstallEvent, Observer},
     info, install_app, list, list_debug_probes, list_serial_ports,
     tabs::tab::Tab,
+    InstallOptions,
 };
 
 #[tokio::main]
@@ -20,9 +24,8 @@ use tockloader_lib::{
 async fn main() -> Result<()> {
     let matches = make_cli().get_matches();
 
-    if matches.get_flag("debug") {
-        println!("Debug mode enabled.");
-    }
+    logger::init(matches.get_flag("debug"));
+    log::debug!("Debug mode enabled.");
 
     match matches.subcommand() {
         Some(("listen", _sub_matches)) => {
@@ -31,112 +34,32 @@ async fn main() -> Result<()> {
                 .context("Failed to run console.")?;
         }
         Some(("list", sub_matches)) => {
-            if sub_matches.get_one::<bool>("serial").is_some() {
-                let serial_ports = list_serial_ports().context("Failed to list serial ports.")?;
-                let port_names: Vec<_> = serial_ports.iter().map(|p| p.port_name.clone()).collect();
-                let ans = Select::new("Which serial port do you want to use?", port_names)
-                    .prompt()
-                    .context("No device is connected.")?;
-                let conn = Connection::open(ConnectionInfo::from(ans), None)
-                    .context("Failed to open serial connection.")?;
-                let mut apps_details = list(conn, None).await.context("Failed to list apps.")?;
-                print_list(&mut apps_details).await;
-            } else {
-                let ans = Select::new("Which debug probe do you want to use?", list_debug_probes())
-                    .prompt()
-                    .context("No debug probe is connected.")?;
-                let conn = Connection::open(
-                    ConnectionInfo::ProbeInfo(ans),
-                    Some(
-                        sub_matches
-                            .get_one::<String>("chip")
-                            .context("No chip has been provided.")?
-                            .to_string(),
-                    ),
-                )
-                .context("Failed to open probe connection.")?;
-                let mut apps_details = list(conn, sub_matches.get_one::<usize>("core"))
-                    .await
-                    .context("Failed to list apps.")?;
-                print_list(&mut apps_details).await;
-            }
+            let mut conn = open_connection(sub_matches).await?;
+            let mut apps_details = list(&mut conn, &Observer::default())
+                .await
+                .context("Failed to list apps.")?;
+            print_list(&mut apps_details).await;
         }
         Some(("info", sub_matches)) => {
-            if sub_matches.get_one::<bool>("serial").is_some() {
-                let serial_ports = list_serial_ports().context("Failed to list serial ports.")?;
-                // Let the user choose the port that will be used
-                let port_names: Vec<_> = serial_ports.iter().map(|p| p.port_name.clone()).collect();
-                let ans = Select::new("Which serial port do you want to use?", port_names)
-                    .prompt()
-                    .context("No device is connected.")?;
-                // Open connection
-                let conn = Connection::open(ConnectionInfo::from(ans), None)
-                    .context("Failed to open serial connection.")?;
-                let mut attributes = info(conn, None)
-                    .await
-                    .context("Failed to get data from the board.")?;
-                print_info(&mut attributes.apps, &mut attributes.system).await;
-            } else {
-                // TODO(Micu Ana): Add error handling
-                let ans = Select::new("Which debug probe do you want to use?", list_debug_probes())
-                    .prompt()
-                    .context("No debug probe is connected.")?;
-                // Open connection
-                let conn = Connection::open(
-                    ConnectionInfo::ProbeInfo(ans),
-                    Some(
-                        sub_matches
-                            .get_one::<String>("chip")
-                            .context("No chip has been provided.")?
-                            .to_string(),
-                    ),
-                )
-                .context("Failed to open probe connection.")?;
-
-                let mut attributes = info(conn, sub_matches.get_one::<usize>("core"))
-                    .await
-                    .context("Failed to get data from the board.")?;
-
-                print_info(&mut attributes.apps, &mut attributes.system).await;
-            }
+            let mut conn = open_connection(sub_matches).await?;
+            let mut attributes = info(&mut conn, &Observer::default())
+                .await
+                .context("Failed to get data from the board.")?;
+            print_info(&mut attributes.apps, &mut attributes.system).await;
         }
         Some(("install", sub_matches)) => {
             let tab_file = Tab::open(sub_matches.get_one::<String>("tab").unwrap().to_string())
                 .context("Failed to use provided tab file.")?;
-            // If "--serial" flag is used, we choose the serial connection
-            if sub_matches.get_one::<bool>("serial").is_some() {
-                let serial_ports = list_serial_ports().context("Failed to list serial ports.")?;
-                // Let the user choose the port that will be used
-                let port_names: Vec<_> = serial_ports.iter().map(|p| p.port_name.clone()).collect();
-                let ans = Select::new("Which serial port do you want to use?", port_names)
-                    .prompt()
-                    .context("No device is connected.")?;
-                // Open connection
-                let conn = Connection::open(ConnectionInfo::from(ans), None)
-                    .context("Failed to open serial connection.")?;
-                // Install app
-                install_app(conn, None, tab_file)
-                    .await
-                    .context("Failed to install app.")?;
-            } else {
-                let ans = Select::new("Which debug probe do you want to use?", list_debug_probes())
-                    .prompt()
-                    .context("No debug probe is connected.")?;
-                let conn = Connection::open(
-                    ConnectionInfo::ProbeInfo(ans),
-                    Some(
-                        sub_matches
-                            .get_one::<String>("chip")
-                            .context("No chip has been provided.")?
-                            .to_string(),
-                    ),
-                )
-                .context("Failed to open probe connection.")?;
-                // Install app
-                install_app(conn, sub_matches.get_one::<usize>("core"), tab_file)
-                    .await
-                    .context("Failed to install app.")?;
-            }
+            let options = InstallOptions {
+                replace: !sub_matches.get_flag("no-replace"),
+                force: sub_matches.get_flag("force"),
+                bundle: sub_matches.get_flag("bundle-apps"),
+            };
+            let mut conn = open_connection(sub_matches).await?;
+            set_page_size(&mut conn, sub_matches)?;
+            install_app(&mut conn, tab_file, &options, &progress_observer())
+                .await
+                .context("Failed to install app.")?;
         }
         _ => {
             println!("Could not run the provided subcommand.");
@@ -145,3 +68,67 @@ async fn main() -> Result<()> {
     }
     Ok(())
 }
+
+/// Open the connection selected by the arguments: a flash file with "--flash-file", the serial
+/// bootloader with "--serial", or else a debug probe. Ports and probes are chosen interactively.
+async fn open_connection(sub_matches: &ArgMatches) -> Result<Connection> {
+    if let Some(path) = sub_matches.get_one::<String>("flash-file") {
+        let info = ConnectionInfo::FlashFileInfo {
+            path: path.into(),
+            base_address: *sub_matches.get_one::<u64>("flash-address").unwrap(),
+            size: sub_matches.get_one::<u64>("flash-size").copied(),
+        };
+        Connection::open(info, None, None)
+            .await
+            .context("Failed to open flash file.")
+    } else if sub_matches.get_flag("serial") {
+        let serial_ports = list_serial_ports().context("Failed to list serial ports.")?;
+        // Let the user choose the port that will be used
+        let port_names: Vec<_> = serial_ports.iter().map(|p| p.port_name.clone()).collect();
+        let ans = Select::new("Which serial port do you want to use?", port_names)
+            .prompt()
+            .context("No device is connected.")?;
+        let info = ConnectionInfo::SerialInfo {
+            port: ans,
+            baud_rate: *sub_matches.get_one::<u32>("baud-rate").unwrap(),
+            no_bootloader_entry: sub_matches.get_flag("no-bootloader-entry"),
+        };
+        Connection::open(info, None, None)
+            .await
+            .context("Failed to open serial connection.")
+    } else {
+        let ans = Select::new("Which debug probe do you want to use?", list_debug_probes())
+            .prompt()
+            .context("No debug probe is connected.")?;
+        Connection::open(
+            ConnectionInfo::ProbeInfo(ans),
+            sub_matches.get_one::<String>("chip").cloned(),
+            sub_matches.get_one::<usize>("core").copied(),
+        )
+        .await
+        .context("Failed to open probe connection.")
+    }
+}
+
+/// Apply the "--page-size" override, 0 meaning that the detected page size is kept.
+fn set_page_size(conn: &mut Connection, sub_matches: &ArgMatches) -> Result<()> {
+    match sub_matches.get_one::<usize>("page-size") {
+        Some(&page_size) if page_size != 0 => conn
+            .set_page_size(page_size)
+            .context("Failed to set the page size."),
+        _ => Ok(()),
+    }
+}
+
+/// Print the installation progress on a single line.
+fn progress_observer() -> Observer {
+    Observer::new(|event| match event {
+        InstallEvent::WritingPage { index, total } => {
+            print!("\rWriting page {}/{}", index + 1, total);
+            let _ = std::io::Write::flush(&mut std::io::stdout());
+        }
+        InstallEvent::Verifying => println!("\nVerifying..."),
+        InstallEvent::Done => println!("Done."),
+        _ => {}
+    })
+}
@@ -0,0 +1,162 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+use tbf_parser::parse::{parse_tbf_header, parse_tbf_header_lengths};
+use tbf_parser::types::TbfHeader;
+
+use crate::connection::TockloaderConnection;
+use crate::errors::TockloaderError;
+use crate::events::{InstallEvent, Observer};
+use crate::layout::{plan_layout, LayoutApp};
+use crate::tbf_header::{padding_tbf, BASE_HEADER_LEN};
+use crate::verify;
+
+/// A TBF found in flash while walking the list of apps.
+pub(crate) struct InstalledApp {
+    pub address: u64,
+    pub header: TbfHeader,
+    /// The whole TBF: header, binary and footers.
+    pub binary: Vec<u8>,
+}
+
+impl InstalledApp {
+    pub fn size(&self) -> u64 {
+        self.binary.len() as u64
+    }
+
+    pub fn end(&self) -> u64 {
+        self.address + self.size()
+    }
+}
+
+/// The header of a TBF found in flash, read without the rest of the TBF.
+pub(crate) struct InstalledHeader {
+    pub address: u64,
+    pub header: TbfHeader,
+    /// Total size of the TBF. Taken from its lengths, as padding headers report a size of 0.
+    pub size: u64,
+}
+
+impl InstalledHeader {
+    pub fn end(&self) -> u64 {
+        self.address + self.size
+    }
+}
+
+/// Walk the apps starting at `appaddr` until erased flash (or anything that is not a TBF) is
+/// found, only reading their headers. Padding TBFs are returned as well, use
+/// [`TbfHeader::is_app`] to skip them.
+pub(crate) async fn read_installed_headers(
+    conn: &mut impl TockloaderConnection,
+    appaddr: u64,
+) -> Result<Vec<InstalledHeader>, TockloaderError> {
+    let mut address = appaddr;
+    let mut headers = Vec::new();
+
+    loop {
+        let buff = conn.read_range(address, 8).await?;
+
+        let lengths = buff.get(0..8).and_then(|bytes| bytes.try_into().ok());
+        let (version, header_len, total_len) = match lengths.map(parse_tbf_header_lengths) {
+            Some(Ok((ver, header_len, whole_len))) if header_len != 0 => {
+                (ver, header_len, whole_len)
+            }
+            _ => break, // No more apps
+        };
+
+        let header_data = conn.read_range(address, header_len as usize).await?;
+        let header =
+            parse_tbf_header(&header_data, version).map_err(TockloaderError::ParsingError)?;
+
+        headers.push(InstalledHeader {
+            address,
+            header,
+            size: total_len as u64,
+        });
+        address += total_len as u64;
+    }
+
+    Ok(headers)
+}
+
+/// Walk the apps starting at `appaddr` like [`read_installed_headers`], reading every TBF in
+/// full.
+pub(crate) async fn read_installed_apps(
+    conn: &mut impl TockloaderConnection,
+    appaddr: u64,
+) -> Result<Vec<InstalledApp>, TockloaderError> {
+    let mut apps = Vec::new();
+    for InstalledHeader {
+        address,
+        header,
+        size,
+    } in read_installed_headers(conn, appaddr).await?
+    {
+        let binary = conn.read_range(address, size as usize).await?;
+        apps.push(InstalledApp {
+            address,
+            header,
+            binary,
+        });
+    }
+
+    Ok(apps)
+}
+
+/// Round `address` up to a multiple of `size`. Apps have to be aligned to their own size for the
+/// MPU to be able to protect them.
+pub(crate) fn align_to_size(address: u64, size: u64) -> u64 {
+    address.div_ceil(size) * size
+}
+
+/// First address after `end` aligned to `alignment` where an app can be placed. The gap left
+/// before it is filled with a padding app, so it is either empty or big enough for a padding
+/// header.
+pub(crate) fn next_app_address(end: u64, alignment: u64) -> u64 {
+    let mut address = align_to_size(end, alignment);
+    while address != end && address - end < BASE_HEADER_LEN as u64 {
+        address += alignment;
+    }
+    address
+}
+
+/// Lay `apps` out from `appaddr` (see [`plan_layout`]) and write the whole app region at once,
+/// replacing whatever was installed up to `old_end`. The flash left after the last app is erased
+/// so it ends the list of apps.
+pub(crate) async fn rewrite_apps(
+    conn: &mut impl TockloaderConnection,
+    appaddr: u64,
+    old_end: u64,
+    apps: Vec<LayoutApp>,
+    mpu_alignment: bool,
+    observer: &Observer,
+) -> Result<(), TockloaderError> {
+    let placements = plan_layout(appaddr, apps, mpu_alignment)?;
+    let new_end = placements
+        .last()
+        .map_or(appaddr, |(address, app)| address + app.len() as u64);
+
+    // Rewrite everything up to the old end of the apps so that no stale TBF header is left after
+    // the last app.
+    let page_size = conn.page_size() as u64;
+    let region_len = align_to_size(old_end.max(new_end) - appaddr, page_size);
+    let mut image = vec![0xFF; region_len as usize];
+    let mut previous_end = appaddr;
+    for (address, app) in &placements {
+        // Padding apps keep the list of apps walkable across alignment gaps
+        if *address != previous_end {
+            let offset = (previous_end - appaddr) as usize;
+            let padding = padding_tbf((address - previous_end) as u32);
+            image[offset..offset + padding.len()].copy_from_slice(&padding);
+        }
+        let offset = (address - appaddr) as usize;
+        image[offset..offset + app.len()].copy_from_slice(app);
+        previous_end = address + app.len() as u64;
+    }
+
+    let ranges = [(appaddr, image)];
+    conn.write_ranges(&ranges, observer).await?;
+    observer.emit(InstallEvent::Verifying);
+    verify(conn, appaddr, &ranges[0].1).await
+}
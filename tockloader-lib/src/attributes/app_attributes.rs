@@ -2,18 +2,16 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
-use probe_rs::{Core, MemoryInterface};
-
 use tbf_parser::{
     self,
     parse::{parse_tbf_footer, parse_tbf_header, parse_tbf_header_lengths},
     types::{TbfFooterV2Credentials, TbfHeader},
 };
-use tokio_serial::SerialStream;
 
 use crate::{
-    bootloader_serial::{issue_command, Command, Response},
+    connection::TockloaderConnection,
     errors::TockloaderError,
+    events::{InstallEvent, Observer},
 };
 
 #[derive(Debug)]
@@ -43,100 +41,17 @@ impl AppAttributes {
     }
 
     // TODO: Document this function
-    pub(crate) fn read_apps_data_probe(
-        board_core: &mut Core,
-        addr: u64,
-    ) -> Result<Vec<AppAttributes>, TockloaderError> {
-        let mut appaddr: u64 = addr;
-        let mut apps_counter = 0;
-        let mut apps_details: Vec<AppAttributes> = vec![];
-
-        loop {
-            let mut appdata = vec![0u8; 8];
-
-            board_core
-                .read(appaddr, &mut appdata)
-                .map_err(TockloaderError::ProbeRsReadError)?;
-
-            let tbf_version: u16;
-            let header_size: u16;
-            let total_size: u32;
-
-            match parse_tbf_header_lengths(
-                &appdata
-                    .try_into()
-                    .expect("Buffer length must be at least 8 bytes long."),
-            ) {
-                Ok(data) => {
-                    tbf_version = data.0;
-                    header_size = data.1;
-                    total_size = data.2;
-                }
-                _ => return Ok(apps_details),
-            };
-
-            let mut header_data = vec![0u8; header_size as usize];
-
-            board_core
-                .read(appaddr, &mut header_data)
-                .map_err(TockloaderError::ProbeRsReadError)?;
-            let header = parse_tbf_header(&header_data, tbf_version)
-                .map_err(TockloaderError::ParsingError)?;
-
-            let binary_end_offset = header.get_binary_end();
-
-            let mut footers: Vec<TbfFooter> = vec![];
-            let total_footers_size = total_size - binary_end_offset;
-            let mut footer_offset = binary_end_offset;
-            let mut footer_number = 0;
-
-            loop {
-                let mut appfooter =
-                    vec![0u8; (total_footers_size - (footer_offset - binary_end_offset)) as usize];
-
-                board_core
-                    .read(appaddr + footer_offset as u64, &mut appfooter)
-                    .map_err(TockloaderError::ProbeRsReadError)?;
-
-                let footer_info =
-                    parse_tbf_footer(&appfooter).map_err(TockloaderError::ParsingError)?;
-
-                footers.insert(footer_number, TbfFooter::new(footer_info.0, footer_info.1));
-
-                footer_number += 1;
-                footer_offset += footer_info.1 + 4;
-
-                if footer_offset == total_size {
-                    break;
-                }
-            }
-
-            let details: AppAttributes = AppAttributes::new(header, footers);
-
-            apps_details.insert(apps_counter, details);
-            apps_counter += 1;
-            appaddr += total_size as u64;
-        }
-    }
-
-    // TODO: Document this function
-    pub(crate) async fn read_apps_data_serial(
-        port: &mut SerialStream,
+    pub(crate) async fn read_apps_data(
+        conn: &mut impl TockloaderConnection,
         addr: u64,
+        observer: &Observer,
     ) -> Result<Vec<AppAttributes>, TockloaderError> {
         let mut appaddr: u64 = addr;
         let mut apps_counter = 0;
         let mut apps_details: Vec<AppAttributes> = vec![];
 
         loop {
-            let mut pkt = (appaddr as u32).to_le_bytes().to_vec();
-            let length = (8_u16).to_le_bytes().to_vec();
-            for i in length {
-                pkt.push(i);
-            }
-
-            let (_, appdata) =
-                issue_command(port, Command::ReadRange, pkt, true, 8, Response::ReadRange).await?;
+            let appdata = conn.read_range(appaddr, 8).await?;
 
             let tbf_version: u16;
             let header_size: u16;
@@ -154,25 +69,18 @@ impl AppAttributes {
                 }
                 _ => break,
             };
+            observer.emit(InstallEvent::ReadingApp { address: appaddr });
 
-            let mut pkt = (appaddr as u32).to_le_bytes().to_vec();
-            let length = (header_size).to_le_bytes().to_vec();
-            for i in length {
-                pkt.push(i);
-            }
-
-            let (_, header_data) = issue_command(
-                port,
-                Command::ReadRange,
-                pkt,
-                true,
-                header_size.into(),
-                Response::ReadRange,
-            )
-            .await?;
+            let header_data = conn.read_range(appaddr, header_size as usize).await?;
 
             let header = parse_tbf_header(&header_data, tbf_version)
                 .map_err(TockloaderError::ParsingError)?;
+
+            // Padding apps only fill gaps between apps, skip them
+            if !header.is_app() {
+                appaddr += total_size as u64;
+                continue;
+            }
             let binary_end_offset = header.get_binary_end();
 
             let mut footers: Vec<TbfFooter> = vec![];
@@ -180,24 +88,13 @@ impl AppAttributes {
             let mut footer_offset = binary_end_offset;
             let mut footer_number = 0;
 
-            loop {
-                let mut pkt = (appaddr as u32 + footer_offset).to_le_bytes().to_vec();
-                let length = ((total_footers_size - (footer_offset - binary_end_offset)) as u16)
-                    .to_le_bytes()
-                    .to_vec();
-                for i in length {
-                    pkt.push(i);
-                }
-
-                let (_, appfooter) = issue_command(
-                    port,
-                    Command::ReadRange,
-                    pkt,
-                    true,
-                    (total_footers_size - (footer_offset - binary_end_offset)) as usize,
-                    Response::ReadRange,
-                )
-                .await?;
+            while footer_offset < total_size {
+                let appfooter = conn
+                    .read_range(
+                        appaddr + footer_offset as u64,
+                        (total_footers_size - (footer_offset - binary_end_offset)) as usize,
+                    )
+                    .await?;
 
                 let footer_info =
                     parse_tbf_footer(&appfooter).map_err(TockloaderError::ParsingError)?;
@@ -206,10 +103,6 @@ impl AppAttributes {
 
                 footer_number += 1;
                 footer_offset += footer_info.1 + 4;
-
-                if footer_offset == total_size {
-                    break;
-                }
             }
 
             let details: AppAttributes = AppAttributes::new(header, footers);
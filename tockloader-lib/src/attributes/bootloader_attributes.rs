@@ -0,0 +1,131 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! Key/value attributes stored by the bootloader in flash.
+//!
+//! The table has 16 slots of 64 bytes. Each slot holds an 8-byte key padded with zeros, the
+//! length of the value and up to 55 bytes of value.
+
+use crate::connection::TockloaderConnection;
+use crate::errors::TockloaderError;
+
+use super::decode::{decode_attribute, DecodedAttribute};
+
+pub const ATTRIBUTES_ADDRESS: u64 = 0x600;
+pub const ATTRIBUTE_COUNT: usize = 16;
+pub const ATTRIBUTE_LEN: usize = 64;
+
+const KEY_LEN: usize = 8;
+const MAX_VALUE_LEN: usize = ATTRIBUTE_LEN - KEY_LEN - 1;
+
+#[derive(Debug)]
+pub struct Attributes {
+    slots: Vec<Result<Option<DecodedAttribute>, TockloaderError>>,
+}
+
+impl Attributes {
+    pub(crate) fn parse(buffer: &[u8]) -> Attributes {
+        Attributes {
+            slots: buffer
+                .chunks(ATTRIBUTE_LEN)
+                .take(ATTRIBUTE_COUNT)
+                .enumerate()
+                .map(|(index, slot)| decode_attribute(index, slot))
+                .collect(),
+        }
+    }
+
+    pub(crate) async fn read(
+        conn: &mut impl TockloaderConnection,
+    ) -> Result<Attributes, TockloaderError> {
+        let buffer = conn
+            .read_range(ATTRIBUTES_ADDRESS, ATTRIBUTE_COUNT * ATTRIBUTE_LEN)
+            .await?;
+        Ok(Attributes::parse(&buffer))
+    }
+
+    /// Every slot of the table, `None` meaning that the slot is empty. Slots that could not be
+    /// decoded do not prevent the others from being used.
+    pub fn slots(&self) -> &[Result<Option<DecodedAttribute>, TockloaderError>] {
+        &self.slots
+    }
+
+    /// Errors of the slots that could not be decoded.
+    pub fn errors(&self) -> impl Iterator<Item = &TockloaderError> {
+        self.slots.iter().filter_map(|slot| slot.as_ref().err())
+    }
+
+    pub(crate) fn into_errors(self) -> Vec<TockloaderError> {
+        self.slots.into_iter().filter_map(Result::err).collect()
+    }
+
+    /// Index of the slot holding `key`.
+    pub fn index_of(&self, key: &str) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Ok(Some(attribute)) if attribute.key == key))
+    }
+
+    /// Value of the attribute called `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.index_of(key)
+            .and_then(|index| match &self.slots[index] {
+                Ok(Some(attribute)) => Some(attribute.value.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Slot where `key` should be written: the one already holding it, or else the first empty
+    /// one. Slots that could not be decoded are never reused.
+    pub fn slot_for(&self, key: &str) -> Option<usize> {
+        self.index_of(key)
+            .or_else(|| self.slots.iter().position(|slot| matches!(slot, Ok(None))))
+    }
+}
+
+/// Encode `key` and `value` as a 64-byte attribute slot.
+pub fn encode_attribute(key: &str, value: &str) -> Result<Vec<u8>, TockloaderError> {
+    if key.is_empty() || key.len() > KEY_LEN {
+        return Err(TockloaderError::InvalidAttribute(format!(
+            "Key \"{}\" must be between 1 and {} bytes long.",
+            key, KEY_LEN
+        )));
+    }
+    if value.is_empty() || value.len() > MAX_VALUE_LEN {
+        return Err(TockloaderError::InvalidAttribute(format!(
+            "Value of \"{}\" must be between 1 and {} bytes long.",
+            key, MAX_VALUE_LEN
+        )));
+    }
+
+    let mut slot = vec![0; ATTRIBUTE_LEN];
+    slot[0..key.len()].copy_from_slice(key.as_bytes());
+    slot[KEY_LEN] = value.len() as u8;
+    slot[KEY_LEN + 1..KEY_LEN + 1 + value.len()].copy_from_slice(value.as_bytes());
+    Ok(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_valid_slots_around_invalid_ones() {
+        let mut buffer = vec![0xFF; ATTRIBUTE_COUNT * ATTRIBUTE_LEN];
+        let board = encode_attribute("board", "nrf52dk").unwrap();
+        buffer[0..ATTRIBUTE_LEN].copy_from_slice(&board);
+        let mut arch = encode_attribute("arch", "cortex-m4").unwrap();
+        arch[KEY_LEN + 1] = 0xC3;
+        buffer[ATTRIBUTE_LEN..2 * ATTRIBUTE_LEN].copy_from_slice(&arch);
+
+        let attributes = Attributes::parse(&buffer);
+        assert_eq!(attributes.get("board"), Some("nrf52dk"));
+        assert_eq!(attributes.get("arch"), None);
+        assert!(matches!(
+            attributes.errors().collect::<Vec<_>>()[..],
+            [TockloaderError::InvalidAttributeEncoding { index: 1, .. }]
+        ));
+        assert_eq!(attributes.slot_for("arch"), Some(2));
+    }
+}
@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
+use crate::errors::TockloaderError;
+
 #[derive(Debug)]
 pub struct DecodedAttribute {
     pub key: String,
@@ -17,43 +19,99 @@ impl DecodedAttribute {
     }
 }
 
-// TODO: explain what is happening here
-pub(crate) fn decode_attribute(step: &[u8]) -> Option<DecodedAttribute> {
-    let raw_key = &step[0..8];
-
-    let decoder_key = utf8_decode::Decoder::new(raw_key.iter().cloned());
+/// Decode the attribute stored in slot `index` of the bootloader attribute table. Slots with a
+/// value length of 0 or more than 55 bytes are empty, which is also the case of erased flash.
+pub(crate) fn decode_attribute(
+    index: usize,
+    step: &[u8],
+) -> Result<Option<DecodedAttribute>, TockloaderError> {
+    let invalid = || TockloaderError::InvalidAttributeEncoding {
+        index,
+        raw: step.to_vec(),
+    };
 
-    let mut key = String::new();
-    for n in decoder_key {
-        key.push(n.expect("Error getting key for attributes."));
+    let vlen = *step.get(8).ok_or_else(invalid)? as usize;
+    if vlen > 55 || vlen == 0 {
+        return Ok(None);
     }
 
-    key = key.trim_end_matches('\0').to_string();
-    let vlen = step[8];
+    let key = bytes_to_string(&step[0..8]).ok_or_else(invalid)?;
+    let value = step
+        .get(9..9 + vlen)
+        .and_then(bytes_to_string)
+        .ok_or_else(invalid)?;
 
-    if vlen > 55 || vlen == 0 {
-        return None;
+    Ok(Some(DecodedAttribute::new(
+        key.trim_end_matches('\0').to_string(),
+        value.trim_end_matches('\0').to_string(),
+    )))
+}
+
+/// Decode the bootloader version, stored as a string padded with zeros. Erased flash means that the
+/// bootloader does not store its version.
+pub(crate) fn decode_bootloader_version(raw: &[u8]) -> Result<Option<String>, TockloaderError> {
+    if raw.iter().all(|&byte| byte == 0xFF) {
+        return Ok(None);
     }
-    let raw_value = &step[9..(9 + vlen as usize)];
-    let decoder_value = utf8_decode::Decoder::new(raw_value.iter().cloned());
+    let version = bytes_to_string(raw)
+        .ok_or_else(|| TockloaderError::InvalidBootloaderVersion(raw.to_vec()))?;
+    Ok(Some(version.trim_matches('\0').to_owned()))
+}
+
+/// Decode UTF-8 `raw` bytes, returning `None` if any of them is invalid.
+pub(crate) fn bytes_to_string(raw: &[u8]) -> Option<String> {
+    utf8_decode::Decoder::new(raw.iter().cloned())
+        .collect::<Result<String, _>>()
+        .ok()
+}
 
-    let mut value = String::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for n in decoder_value {
-        value.push(n.expect("Error getting key for attributes."));
+    fn slot(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut slot = vec![0; 64];
+        slot[0..key.len()].copy_from_slice(key);
+        slot[8] = value.len() as u8;
+        slot[9..9 + value.len()].copy_from_slice(value);
+        slot
     }
 
-    value = value.trim_end_matches('\0').to_string();
-    Some(DecodedAttribute::new(key, value))
-}
+    #[test]
+    fn decodes_attributes() {
+        let attribute = decode_attribute(0, &slot(b"board", b"nrf52dk"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(attribute.key, "board");
+        assert_eq!(attribute.value, "nrf52dk");
+        assert!(decode_attribute(0, &[0xFF; 64]).unwrap().is_none());
+    }
 
-// TODO: explain what is happening here
-pub(crate) fn bytes_to_string(raw: &[u8]) -> String {
-    let decoder = utf8_decode::Decoder::new(raw.iter().cloned());
+    #[test]
+    fn rejects_invalid_utf8() {
+        let value = slot(b"board", b"nrf\xC3\x28");
+        assert!(matches!(
+            decode_attribute(3, &value),
+            Err(TockloaderError::InvalidAttributeEncoding { index: 3, raw }) if raw == value
+        ));
+        assert!(matches!(
+            decode_attribute(3, &slot(b"bo\xFFrd", b"nrf52dk")),
+            Err(TockloaderError::InvalidAttributeEncoding { index: 3, .. })
+        ));
+    }
 
-    let mut string = String::new();
-    for n in decoder {
-        string.push(n.expect("Error getting key for attributes."));
+    #[test]
+    fn decodes_bootloader_versions() {
+        assert_eq!(
+            decode_bootloader_version(b"1.1.0\0\0\0")
+                .unwrap()
+                .as_deref(),
+            Some("1.1.0")
+        );
+        assert!(decode_bootloader_version(&[0xFF; 8]).unwrap().is_none());
+        assert!(matches!(
+            decode_bootloader_version(b"1.1\xFF\0\0\0\0"),
+            Err(TockloaderError::InvalidBootloaderVersion(raw)) if raw == b"1.1\xFF\0\0\0\0"
+        ));
     }
-    string
 }
@@ -0,0 +1,239 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! Attributes stored by the Tock kernel at the end of its flash region, right before the apps.
+//!
+//! Read from the end, the structure is the "TOCK" sentinel, a version byte, 3 reserved bytes and
+//! a list of TLVs growing backwards. Each TLV ends with its type and length (2 bytes each) and
+//! its value is stored right before them.
+
+use byteorder::{ByteOrder, LittleEndian};
+use thiserror::Error;
+
+const SENTINEL: &[u8; 4] = b"TOCK";
+// Sentinel, version and reserved bytes
+const FOOTER_LEN: usize = 8;
+const TLV_HEADER_LEN: usize = 4;
+
+const TLV_APP_MEMORY: u16 = 0x0101;
+const TLV_KERNEL_BINARY: u16 = 0x0102;
+
+#[derive(Debug, Error)]
+pub enum KernelAttributesError {
+    #[error("Expected at least {FOOTER_LEN} bytes of kernel attributes, got {0}.")]
+    TooShort(usize),
+
+    #[error("Kernel attributes version {0} is not supported.")]
+    UnsupportedVersion(u8),
+
+    #[error("Kernel attribute TLV {tipe:#06x} is {length} bytes long, but only {available} bytes are left.")]
+    TruncatedTlv {
+        tipe: u16,
+        length: u16,
+        available: usize,
+    },
+
+    #[error("Kernel attribute TLV {tipe:#06x} should be {expected} bytes long, not {length}.")]
+    BadTlvLength {
+        tipe: u16,
+        length: u16,
+        expected: u16,
+    },
+}
+
+/// A region of memory described by its start address and length.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRange {
+    pub start: u32,
+    pub len: u32,
+}
+
+impl MemoryRange {
+    fn parse(tipe: u16, value: &[u8]) -> Result<MemoryRange, KernelAttributesError> {
+        if value.len() != 8 {
+            return Err(KernelAttributesError::BadTlvLength {
+                tipe,
+                length: value.len() as u16,
+                expected: 8,
+            });
+        }
+        Ok(MemoryRange {
+            start: LittleEndian::read_u32(&value[0..4]),
+            len: LittleEndian::read_u32(&value[4..8]),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct KernelAttributes {
+    pub version: u8,
+    /// Region of RAM the kernel gives to apps.
+    pub app_memory: Option<MemoryRange>,
+    /// Region of flash holding the kernel itself.
+    pub kernel_binary: Option<MemoryRange>,
+}
+
+impl KernelAttributes {
+    /// Parse the kernel attributes ending at the end of `buffer`. Returns `None` if there is no
+    /// sentinel, as kernels older than Tock 2.1 do not store attributes.
+    pub(crate) fn parse(buffer: &[u8]) -> Result<Option<KernelAttributes>, KernelAttributesError> {
+        if buffer.len() < FOOTER_LEN {
+            return Err(KernelAttributesError::TooShort(buffer.len()));
+        }
+
+        let (mut remaining, footer) = buffer.split_at(buffer.len() - FOOTER_LEN);
+        if &footer[4..8] != SENTINEL {
+            return Ok(None);
+        }
+
+        let version = footer[3];
+        if version != 1 {
+            return Err(KernelAttributesError::UnsupportedVersion(version));
+        }
+
+        let mut attributes = KernelAttributes {
+            version,
+            app_memory: None,
+            kernel_binary: None,
+        };
+
+        // The TLVs are followed (going backwards) by the end of the kernel, so the list ends at
+        // the first type that is not known
+        while remaining.len() >= TLV_HEADER_LEN {
+            let (rest, tlv_header) = remaining.split_at(remaining.len() - TLV_HEADER_LEN);
+            let tipe = LittleEndian::read_u16(&tlv_header[0..2]);
+            let length = LittleEndian::read_u16(&tlv_header[2..4]);
+
+            if tipe != TLV_APP_MEMORY && tipe != TLV_KERNEL_BINARY {
+                break;
+            }
+            if length as usize > rest.len() {
+                return Err(KernelAttributesError::TruncatedTlv {
+                    tipe,
+                    length,
+                    available: rest.len(),
+                });
+            }
+
+            let (rest, value) = rest.split_at(rest.len() - length as usize);
+            let range = MemoryRange::parse(tipe, value)?;
+            if tipe == TLV_APP_MEMORY {
+                attributes.app_memory = Some(range);
+            } else {
+                attributes.kernel_binary = Some(range);
+            }
+            remaining = rest;
+        }
+
+        Ok(Some(attributes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Kernel attributes with `tlvs`, given as `(type, value)` pairs in the order they are read,
+    /// from the end of the buffer.
+    fn attributes(version: u8, tlvs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for (tipe, value) in tlvs.iter().rev() {
+            buffer.extend_from_slice(value);
+            buffer.extend(tipe.to_le_bytes());
+            buffer.extend((value.len() as u16).to_le_bytes());
+        }
+        buffer.extend([0, 0, 0, version]);
+        buffer.extend(SENTINEL);
+        buffer
+    }
+
+    fn range(start: u32, len: u32) -> Vec<u8> {
+        [start.to_le_bytes(), len.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn no_sentinel() {
+        assert!(KernelAttributes::parse(&[0xFF; 100]).unwrap().is_none());
+        assert!(matches!(
+            KernelAttributes::parse(b"TOCK"),
+            Err(KernelAttributesError::TooShort(4))
+        ));
+    }
+
+    #[test]
+    fn version_1_with_both_tlvs() {
+        let mut buffer = vec![0xFF; 20];
+        buffer.extend(attributes(
+            1,
+            &[
+                (TLV_APP_MEMORY, &range(0x2000_8000, 0x8000)),
+                (TLV_KERNEL_BINARY, &range(0x0001_0000, 0x2_0000)),
+            ],
+        ));
+
+        let attributes = KernelAttributes::parse(&buffer).unwrap().unwrap();
+        assert_eq!(attributes.version, 1);
+        let app_memory = attributes.app_memory.unwrap();
+        assert_eq!((app_memory.start, app_memory.len), (0x2000_8000, 0x8000));
+        let kernel_binary = attributes.kernel_binary.unwrap();
+        assert_eq!(
+            (kernel_binary.start, kernel_binary.len),
+            (0x0001_0000, 0x2_0000)
+        );
+    }
+
+    #[test]
+    fn unknown_tlv_ends_the_list() {
+        let buffer = attributes(
+            1,
+            &[
+                (TLV_APP_MEMORY, &range(0x2000_8000, 0x8000)),
+                (0x0200, &range(0, 0)),
+                (TLV_KERNEL_BINARY, &range(0x0001_0000, 0x2_0000)),
+            ],
+        );
+
+        let attributes = KernelAttributes::parse(&buffer).unwrap().unwrap();
+        assert!(attributes.app_memory.is_some());
+        assert!(attributes.kernel_binary.is_none());
+    }
+
+    #[test]
+    fn truncated_tlv() {
+        let buffer = attributes(1, &[(TLV_APP_MEMORY, &range(0x2000_8000, 0x8000))]);
+
+        assert!(matches!(
+            KernelAttributes::parse(&buffer[4..]),
+            Err(KernelAttributesError::TruncatedTlv {
+                tipe: TLV_APP_MEMORY,
+                length: 8,
+                available: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn bad_tlv_length() {
+        let buffer = attributes(1, &[(TLV_KERNEL_BINARY, &[0; 4])]);
+
+        assert!(matches!(
+            KernelAttributes::parse(&buffer),
+            Err(KernelAttributesError::BadTlvLength {
+                tipe: TLV_KERNEL_BINARY,
+                length: 4,
+                expected: 8,
+            })
+        ));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let buffer = attributes(2, &[(TLV_APP_MEMORY, &[0; 3])]);
+
+        assert!(matches!(
+            KernelAttributes::parse(&buffer),
+            Err(KernelAttributesError::UnsupportedVersion(2))
+        ));
+    }
+}
@@ -3,6 +3,8 @@
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
 pub mod app_attributes;
+pub mod bootloader_attributes;
 pub mod decode;
 pub mod general_attributes;
+pub mod kernel_attributes;
 pub mod system_attributes;
@@ -2,22 +2,24 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
-use byteorder::{ByteOrder, LittleEndian};
-use probe_rs::{Core, MemoryInterface};
-use tokio_serial::SerialStream;
+use crate::{connection::TockloaderConnection, errors::TockloaderError};
 
-use crate::{
-    bootloader_serial::{issue_command, Command, Response},
-    errors::TockloaderError,
-};
+use super::bootloader_attributes::Attributes;
+use super::decode::decode_bootloader_version;
+use super::kernel_attributes::{KernelAttributes, KernelAttributesError};
 
-use super::decode::{bytes_to_string, decode_attribute};
+// Kernel attributes are read from the end of the kernel, right before the apps.
+const KERNEL_ATTRIBUTES_LEN: u64 = 100;
+
+// Kernel attributes were introduced with Tock 2.1.
+const KERNEL_ATTRIBUTES_KERNEL_VERSION: (u16, u16) = (2, 1);
 
 #[derive(Debug)]
 pub struct SystemAttributes {
     pub board: Option<String>,
     pub arch: Option<String>,
-    pub appaddr: Option<u64>,
+    /// Address of the first app. Boards without it are rejected when the attributes are read.
+    pub appaddr: u64,
     pub boothash: Option<String>,
     pub bootloader_version: Option<String>,
     pub sentinel: Option<String>,
@@ -26,14 +28,19 @@ pub struct SystemAttributes {
     pub app_mem_len: Option<u32>,
     pub kernel_bin_start: Option<u32>,
     pub kernel_bin_len: Option<u32>,
+    /// Bootloader attribute slots that could not be decoded. The other attributes are still read.
+    pub invalid_attributes: Vec<TockloaderError>,
+    /// Why the TLVs of the kernel attributes were skipped. The sentinel and the version are still
+    /// read.
+    pub invalid_kernel_attributes: Option<KernelAttributesError>,
 }
 
 impl SystemAttributes {
-    pub(crate) fn new() -> SystemAttributes {
+    pub(crate) fn new(appaddr: u64) -> SystemAttributes {
         SystemAttributes {
             board: None,
             arch: None,
-            appaddr: None,
+            appaddr,
             boothash: None,
             bootloader_version: None,
             sentinel: None,
@@ -42,229 +49,83 @@ impl SystemAttributes {
             app_mem_len: None,
             kernel_bin_start: None,
             kernel_bin_len: None,
+            invalid_attributes: Vec::new(),
+            invalid_kernel_attributes: None,
         }
     }
 
-    // TODO: explain what is happening here
-    pub(crate) fn read_system_attributes_probe(
-        board_core: &mut Core,
-    ) -> Result<Self, TockloaderError> {
-        let mut result = SystemAttributes::new();
-        let address = 0x600;
-        let mut buf = [0u8; 64 * 16];
-
-        let _ = board_core.read(address, &mut buf);
-
-        let mut data = buf.chunks(64);
-
-        for index_data in 0..data.len() {
-            let step = match data.next() {
-                Some(data) => data,
-                None => break,
-            };
-
-            let step_option = decode_attribute(step);
-
-            if let Some(decoded_attributes) = step_option {
-                match index_data {
-                    0 => {
-                        result.board = Some(decoded_attributes.value.to_string());
-                    }
-                    1 => {
-                        result.arch = Some(decoded_attributes.value.to_string());
-                    }
-                    2 => {
-                        result.appaddr = Some(
-                            u64::from_str_radix(
-                                decoded_attributes
-                                    .value
-                                    .to_string()
-                                    .trim_start_matches("0x"),
-                                16,
-                            )
-                            .map_err(|_| {
-                                TockloaderError::MisconfiguredBoard(
-                                    "Invalid start address.".to_owned(),
-                                )
-                            })?,
-                        );
-                    }
-                    3 => {
-                        result.boothash = Some(decoded_attributes.value.to_string());
-                    }
-                    _ => {}
-                }
-            } else {
-                continue;
-            }
-        }
-
-        let address = 0x40E;
-
-        let mut buf = [0u8; 8];
-
-        let _ = board_core.read_8(address, &mut buf);
-
-        let string = String::from_utf8(buf.to_vec()).map_err(|_| {
-            TockloaderError::MisconfiguredBoard(
-                "Data may be corrupted. System attribure is not UTF-8.".to_owned(),
-            )
-        })?;
-
-        let string = string.trim_matches(char::from(0));
-
-        result.bootloader_version = Some(string.to_owned());
-
-        let mut kernel_attr_binary = [0u8; 100];
-        board_core
-            .read(
-                result.appaddr.ok_or(TockloaderError::MisconfiguredBoard(
-                    "No start address found.".to_owned(),
-                ))? - 100,
-                &mut kernel_attr_binary,
-            )
-            .map_err(TockloaderError::ProbeRsReadError)?;
-
-        let sentinel = bytes_to_string(&kernel_attr_binary[96..100]);
-        let kernel_version = LittleEndian::read_uint(&kernel_attr_binary[95..96], 1);
-
-        let app_memory_len = LittleEndian::read_u32(&kernel_attr_binary[84..92]);
-        let app_memory_start = LittleEndian::read_u32(&kernel_attr_binary[80..84]);
-
-        let kernel_binary_start = LittleEndian::read_u32(&kernel_attr_binary[68..72]);
-        let kernel_binary_len = LittleEndian::read_u32(&kernel_attr_binary[72..76]);
-
-        result.sentinel = Some(sentinel);
-        result.kernel_version = Some(kernel_version);
-        result.app_mem_start = Some(app_memory_start);
-        result.app_mem_len = Some(app_memory_len);
-        result.kernel_bin_start = Some(kernel_binary_start);
-        result.kernel_bin_len = Some(kernel_binary_len);
-
-        Ok(result)
+    /// Oldest version, as `(major, minor)`, the kernel running on the board can have, if it can
+    /// be told. The kernel does not store its version, but only kernels since Tock 2.1 have kernel
+    /// attributes.
+    pub fn minimum_kernel_version(&self) -> Option<(u16, u16)> {
+        self.sentinel
+            .as_ref()
+            .map(|_| KERNEL_ATTRIBUTES_KERNEL_VERSION)
     }
 
     // TODO: explain what is happening here
-    pub(crate) async fn read_system_attributes_serial(
-        port: &mut SerialStream,
+    pub(crate) async fn read_system_attributes(
+        conn: &mut impl TockloaderConnection,
     ) -> Result<Self, TockloaderError> {
-        let mut result = SystemAttributes::new();
-
-        let mut pkt = (0x600_u32).to_le_bytes().to_vec();
-        let length = (1024_u16).to_le_bytes().to_vec();
-        for i in length {
-            pkt.push(i);
+        let attributes = Attributes::read(conn).await?;
+
+        let appaddr = attributes
+            .get("appaddr")
+            .ok_or(TockloaderError::MisconfiguredBoard(
+                "No start address found.".to_owned(),
+            ))?;
+        let appaddr = u64::from_str_radix(appaddr.trim_start_matches("0x"), 16).map_err(|_| {
+            TockloaderError::MisconfiguredBoard("Invalid start address.".to_owned())
+        })?;
+        let mut result = SystemAttributes::new(appaddr);
+
+        result.board = attributes.get("board").map(str::to_owned);
+        result.arch = attributes.get("arch").map(str::to_owned);
+        result.boothash = attributes.get("boothash").map(str::to_owned);
+        result.invalid_attributes = attributes.into_errors();
+
+        // A garbled version does not prevent the rest of the board from being used
+        let buf = conn.read_range(0x40E, 8).await?;
+        match decode_bootloader_version(&buf) {
+            Ok(version) => result.bootloader_version = version,
+            Err(e) => result.invalid_attributes.push(e),
         }
 
-        let (_, buf) = issue_command(
-            port,
-            Command::ReadRange,
-            pkt,
-            true,
-            64 * 16,
-            Response::ReadRange,
-        )
-        .await?;
-
-        let mut data = buf.chunks(64);
-
-        for index_data in 0..data.len() {
-            let step = match data.next() {
-                Some(data) => data,
-                None => break,
-            };
-
-            let step_option = decode_attribute(step);
-
-            if let Some(decoded_attributes) = step_option {
-                match index_data {
-                    0 => {
-                        result.board = Some(decoded_attributes.value.to_string());
-                    }
-                    1 => {
-                        result.arch = Some(decoded_attributes.value.to_string());
-                    }
-                    2 => {
-                        result.appaddr = Some(
-                            u64::from_str_radix(
-                                decoded_attributes
-                                    .value
-                                    .to_string()
-                                    .trim_start_matches("0x"),
-                                16,
-                            )
-                            .map_err(|_| {
-                                TockloaderError::MisconfiguredBoard(
-                                    "Invalid start address.".to_owned(),
-                                )
-                            })?,
-                        );
-                    }
-                    3 => {
-                        result.boothash = Some(decoded_attributes.value.to_string());
-                    }
-                    _ => {}
+        let kernel_attributes_address = appaddr.checked_sub(KERNEL_ATTRIBUTES_LEN).ok_or(
+            TockloaderError::MisconfiguredBoard(format!(
+                "Start address {appaddr:#x} leaves no room for kernel attributes."
+            )),
+        )?;
+        let kernel_attr_binary = conn
+            .read_range(kernel_attributes_address, KERNEL_ATTRIBUTES_LEN as usize)
+            .await?;
+
+        // Kernels without attributes leave these fields empty
+        match KernelAttributes::parse(&kernel_attr_binary) {
+            Ok(None) => {}
+            Ok(Some(kernel_attributes)) => {
+                result.sentinel = Some("TOCK".to_owned());
+                result.kernel_version = Some(kernel_attributes.version as u64);
+                if let Some(app_memory) = kernel_attributes.app_memory {
+                    result.app_mem_start = Some(app_memory.start);
+                    result.app_mem_len = Some(app_memory.len);
+                }
+                if let Some(kernel_binary) = kernel_attributes.kernel_binary {
+                    result.kernel_bin_start = Some(kernel_binary.start);
+                    result.kernel_bin_len = Some(kernel_binary.len);
                 }
-            } else {
-                continue;
             }
+            // Newer kernels may lay their TLVs out differently, the rest of the board is still
+            // usable
+            Err(e @ KernelAttributesError::UnsupportedVersion(version)) => {
+                log::warn!("{}", e);
+                result.sentinel = Some("TOCK".to_owned());
+                result.kernel_version = Some(version as u64);
+                result.invalid_kernel_attributes = Some(e);
+            }
+            Err(e) => return Err(TockloaderError::InvalidKernelAttributes(e)),
         }
 
-        let mut pkt = (0x40E_u32).to_le_bytes().to_vec();
-        let length = (8_u16).to_le_bytes().to_vec();
-        for i in length {
-            pkt.push(i);
-        }
-
-        let (_, buf) =
-            issue_command(port, Command::ReadRange, pkt, true, 8, Response::ReadRange).await?;
-
-        let string = String::from_utf8(buf).map_err(|_| {
-            TockloaderError::MisconfiguredBoard(
-                "Data may be corrupted. System attribure is not UTF-8.".to_owned(),
-            )
-        })?;
-
-        let string = string.trim_matches(char::from(0));
-
-        result.bootloader_version = Some(string.to_owned());
-
-        let mut pkt = ((result.appaddr.ok_or(TockloaderError::MisconfiguredBoard(
-            "No start address found.".to_owned(),
-        ))? - 100) as u32)
-            .to_le_bytes()
-            .to_vec();
-        let length = (100_u16).to_le_bytes().to_vec();
-        for i in length {
-            pkt.push(i);
-        }
-
-        let (_, kernel_attr_binary) = issue_command(
-            port,
-            Command::ReadRange,
-            pkt,
-            true,
-            100,
-            Response::ReadRange,
-        )
-        .await?;
-
-        let sentinel = bytes_to_string(&kernel_attr_binary[96..100]);
-        let kernel_version = LittleEndian::read_uint(&kernel_attr_binary[95..96], 1);
-
-        let app_memory_len = LittleEndian::read_u32(&kernel_attr_binary[84..92]);
-        let app_memory_start = LittleEndian::read_u32(&kernel_attr_binary[80..84]);
-
-        let kernel_binary_start = LittleEndian::read_u32(&kernel_attr_binary[68..72]);
-        let kernel_binary_len = LittleEndian::read_u32(&kernel_attr_binary[72..76]);
-
-        result.sentinel = Some(sentinel);
-        result.kernel_version = Some(kernel_version);
-        result.app_mem_start = Some(app_memory_start);
-        result.app_mem_len = Some(app_memory_len);
-        result.kernel_bin_start = Some(kernel_binary_start);
-        result.kernel_bin_len = Some(kernel_binary_len);
-
         Ok(result)
     }
 }
@@ -0,0 +1,347 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! Framing of the serial bootloader protocol.
+//!
+//! A command is its escaped arguments followed by the escape character and the command code. A
+//! response is the escape character and the response code followed by its escaped data. Escaping
+//! doubles every escape character. Responses are not delimited, so the length of their data is
+//! derived from the command they answer.
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::attributes::bootloader_attributes::ATTRIBUTE_LEN;
+use crate::errors::{BootloaderError, TockloaderError};
+
+use super::{Command, Response, ESCAPE_CHAR};
+
+// The info response is a length byte followed by the info string, padded with zeros.
+pub(crate) const INFO_LEN: usize = 193;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CommandFrame {
+    pub code: u8,
+    pub args: Vec<u8>,
+}
+
+impl CommandFrame {
+    pub fn new(command: Command, args: Vec<u8>) -> CommandFrame {
+        CommandFrame {
+            code: command as u8,
+            args,
+        }
+    }
+
+    /// The sync message, which makes the bootloader drop what it received so far. It is a reset
+    /// command with a single zero byte as argument.
+    pub fn sync() -> CommandFrame {
+        CommandFrame::new(Command::Reset, vec![0x00])
+    }
+
+    pub fn command(&self) -> Result<Command, u8> {
+        Command::try_from(self.code)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ResponseFrame {
+    pub code: u8,
+    pub data: Vec<u8>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl ResponseFrame {
+    pub fn new(response: Response, data: Vec<u8>) -> ResponseFrame {
+        ResponseFrame {
+            code: response as u8,
+            data,
+        }
+    }
+}
+
+/// Response a successful `command` gets and the length of its data, or `None` for the commands
+/// that are not answered.
+pub(crate) fn expected_response(command: Command, args: &[u8]) -> Option<(Response, usize)> {
+    let range_length = || match args {
+        [_, _, _, _, l0, l1, ..] => u16::from_le_bytes([*l0, *l1]) as usize,
+        _ => 0,
+    };
+
+    match command {
+        Command::Reset | Command::Exit => None,
+        Command::Ping => Some((Response::Pong, 0)),
+        Command::Info => Some((Response::Info, INFO_LEN)),
+        Command::ReadRange => Some((Response::ReadRange, range_length())),
+        Command::XRRange => Some((Response::XRRange, range_length())),
+        Command::GetAttribute => Some((Response::GetAttribute, ATTRIBUTE_LEN)),
+        Command::CRCInternalFlash => Some((Response::CRCInternalFlash, 4)),
+        Command::Crcef => Some((Response::Crcxf, 4)),
+        _ => Some((Response::OK, 0)),
+    }
+}
+
+fn escape(data: &[u8], dst: &mut BytesMut) {
+    dst.reserve(data.len());
+    for &byte in data {
+        dst.put_u8(byte);
+        if byte == ESCAPE_CHAR {
+            dst.put_u8(ESCAPE_CHAR);
+        }
+    }
+}
+
+/// Host side of the protocol: encodes commands and decodes their responses.
+#[derive(Debug, Default)]
+pub(crate) struct HostCodec {
+    // Response code and data length of the last command sent that expects an answer
+    expected: Option<(u8, usize)>,
+}
+
+impl Encoder<CommandFrame> for HostCodec {
+    type Error = TockloaderError;
+
+    fn encode(&mut self, frame: CommandFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if let Ok(command) = frame.command() {
+            if let Some((response, length)) = expected_response(command, &frame.args) {
+                self.expected = Some((response as u8, length));
+            }
+        }
+
+        escape(&frame.args, dst);
+        dst.put_u8(ESCAPE_CHAR);
+        dst.put_u8(frame.code);
+        Ok(())
+    }
+}
+
+impl Decoder for HostCodec {
+    type Item = ResponseFrame;
+    type Error = TockloaderError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let [first, code, ..] = src[..] else {
+            return Ok(None);
+        };
+        if first != ESCAPE_CHAR {
+            return Err(BootloaderError::Garbled(vec![first, code]).into());
+        }
+
+        // Errors are not followed by data
+        let length = match self.expected {
+            Some((expected_code, length)) if expected_code == code => length,
+            _ => 0,
+        };
+
+        let mut data = Vec::with_capacity(length);
+        let mut position = 2;
+        while data.len() < length {
+            match src[position..] {
+                [ESCAPE_CHAR, ESCAPE_CHAR, ..] => position += 2,
+                [ESCAPE_CHAR, other, ..] => {
+                    return Err(BootloaderError::Garbled(vec![ESCAPE_CHAR, other]).into())
+                }
+                [ESCAPE_CHAR] | [] => return Ok(None),
+                [_, ..] => position += 1,
+            }
+            data.push(src[position - 1]);
+        }
+
+        src.advance(position);
+        self.expected = None;
+        Ok(Some(ResponseFrame { code, data }))
+    }
+}
+
+/// Bootloader side of the protocol: decodes commands and encodes responses. Only the simulated
+/// bootloader needs it.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Default)]
+pub(crate) struct BootloaderCodec {}
+
+#[cfg(any(test, feature = "testing"))]
+impl Encoder<ResponseFrame> for BootloaderCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: ResponseFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_u8(ESCAPE_CHAR);
+        dst.put_u8(frame.code);
+        escape(&frame.data, dst);
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Decoder for BootloaderCodec {
+    type Item = CommandFrame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut args = Vec::new();
+        let mut position = 0;
+        loop {
+            match src[position..] {
+                [ESCAPE_CHAR, ESCAPE_CHAR, ..] => {
+                    args.push(ESCAPE_CHAR);
+                    position += 2;
+                }
+                // Any other byte after an escape character ends the command
+                [ESCAPE_CHAR, code, ..] => {
+                    src.advance(position + 2);
+                    return Ok(Some(CommandFrame { code, args }));
+                }
+                [ESCAPE_CHAR] | [] => return Ok(None),
+                [byte, ..] => {
+                    args.push(byte);
+                    position += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_command(codec: &mut HostCodec, frame: CommandFrame) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        codec.encode(frame, &mut buffer).unwrap();
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn escapes_command_arguments() {
+        let frame = CommandFrame::new(Command::WritePage, vec![0x01, 0xFC, 0xFC, 0x02, 0xFC]);
+        assert_eq!(
+            encode_command(&mut HostCodec::default(), frame),
+            [0x01, 0xFC, 0xFC, 0xFC, 0xFC, 0x02, 0xFC, 0xFC, 0xFC, 0x07]
+        );
+    }
+
+    #[test]
+    fn sync_frame_is_the_sync_message() {
+        assert_eq!(
+            encode_command(&mut HostCodec::default(), CommandFrame::sync()),
+            super::super::SYNC_MESSAGE
+        );
+    }
+
+    #[test]
+    fn decodes_escaped_response_data() {
+        let mut codec = HostCodec::default();
+        let read_range = CommandFrame::new(Command::ReadRange, vec![0, 0, 0, 0, 4, 0]);
+        encode_command(&mut codec, read_range);
+
+        // Two adjacent escaped bytes, followed by the start of another response
+        let mut src =
+            BytesMut::from(&[0xFC, 0x20, 0xFC, 0xFC, 0xFC, 0xFC, 0x01, 0xFC, 0xFC, 0xFC][..]);
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            ResponseFrame::new(Response::ReadRange, vec![0xFC, 0xFC, 0x01, 0xFC])
+        );
+        assert_eq!(&src[..], [0xFC]);
+    }
+
+    #[test]
+    fn waits_for_whole_response() {
+        let mut codec = HostCodec::default();
+        encode_command(
+            &mut codec,
+            CommandFrame::new(Command::CRCInternalFlash, vec![0; 8]),
+        );
+
+        let response = [0xFC, 0x23, 0x01, 0xFC, 0xFC, 0x02, 0x03];
+        let mut src = BytesMut::new();
+        for (index, &byte) in response.iter().enumerate() {
+            // The escape character alone is not a whole data byte yet
+            assert_eq!(codec.decode(&mut src).unwrap(), None, "after {index} bytes");
+            src.put_u8(byte);
+        }
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(frame.data, [0x01, 0xFC, 0x02, 0x03]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn errors_have_no_data() {
+        let mut codec = HostCodec::default();
+        encode_command(
+            &mut codec,
+            CommandFrame::new(Command::ReadRange, vec![0, 0, 0, 0, 16, 0]),
+        );
+
+        let mut src = BytesMut::from(&[0xFC, 0x12][..]);
+        let frame = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(frame, ResponseFrame::new(Response::BadAddr, Vec::new()));
+    }
+
+    #[test]
+    fn rejects_garbled_responses() {
+        let mut codec = HostCodec::default();
+        encode_command(&mut codec, CommandFrame::new(Command::Ping, Vec::new()));
+        let mut src = BytesMut::from(&[0x00, 0x11][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(TockloaderError::BootloaderError(BootloaderError::Garbled(
+                _
+            )))
+        ));
+
+        encode_command(
+            &mut codec,
+            CommandFrame::new(Command::CRCInternalFlash, vec![0; 8]),
+        );
+        let mut src = BytesMut::from(&[0xFC, 0x23, 0x01, 0xFC, 0x15, 0x02][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(TockloaderError::BootloaderError(BootloaderError::Garbled(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_the_bootloader_codec() {
+        let args: Vec<u8> = (0..=255).chain([0xFC, 0xFC]).collect();
+        let mut host = HostCodec::default();
+        let mut bootloader = BootloaderCodec::default();
+
+        let mut wire = BytesMut::new();
+        host.encode(CommandFrame::sync(), &mut wire).unwrap();
+        host.encode(
+            CommandFrame::new(Command::WritePage, args.clone()),
+            &mut wire,
+        )
+        .unwrap();
+        assert_eq!(
+            bootloader.decode(&mut wire).unwrap(),
+            Some(CommandFrame::sync())
+        );
+        assert_eq!(
+            bootloader.decode(&mut wire).unwrap(),
+            Some(CommandFrame::new(Command::WritePage, args.clone()))
+        );
+        assert_eq!(bootloader.decode(&mut wire).unwrap(), None);
+
+        let length = (args.len() as u16).to_le_bytes();
+        host.encode(
+            CommandFrame::new(Command::ReadRange, vec![0, 0, 0, 0, length[0], length[1]]),
+            &mut wire,
+        )
+        .unwrap();
+        wire.clear();
+        bootloader
+            .encode(
+                ResponseFrame::new(Response::ReadRange, args.clone()),
+                &mut wire,
+            )
+            .unwrap();
+        assert_eq!(
+            host.decode(&mut wire).unwrap(),
+            Some(ResponseFrame::new(Response::ReadRange, args))
+        );
+    }
+}
@@ -0,0 +1,261 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+// The "X" commands are for external flash
+
+pub(crate) mod codec;
+
+use crate::errors;
+use codec::{expected_response, CommandFrame, HostCodec};
+use errors::{BootloaderError, TockloaderError};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{SerialPort, SerialStream};
+use tokio_util::codec::Framed;
+
+// Tell the bootloader to reset its buffer to handle a new command
+pub const SYNC_MESSAGE: [u8; 3] = [0x00, 0xFC, 0x05];
+
+// "This was chosen as it is infrequent in .bin files" - immesys
+pub const ESCAPE_CHAR: u8 = 0xFC;
+
+// First argument of the ChangeBaudRate command: ask for a new baud rate, then confirm it once
+// both ends use it.
+pub(crate) const BAUD_RATE_REQUEST: u8 = 0x01;
+pub(crate) const BAUD_RATE_CONFIRM: u8 = 0x02;
+
+// How many times the bootloader is pinged before giving up, and how long each ping waits.
+const PING_ATTEMPTS: usize = 30;
+const PING_TIMEOUT: Duration = Duration::from_millis(100);
+
+// The rest of a garbled response is dropped once nothing has been received for this long.
+const RESYNC_QUIET_TIME: Duration = Duration::from_millis(50);
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Command {
+    // Commands from this tool to the bootloader
+    Ping = 0x01,
+    Info = 0x03,
+    ID = 0x04,
+    Reset = 0x05,
+    ErasePage = 0x06,
+    WritePage = 0x07,
+    XEBlock = 0x08,
+    XWPage = 0x09,
+    Crcx = 0x10,
+    ReadRange = 0x11,
+    XRRange = 0x12,
+    SetAttribute = 0x13,
+    GetAttribute = 0x14,
+    CRCInternalFlash = 0x15,
+    Crcef = 0x16,
+    XEPage = 0x17,
+    XFinit = 0x18,
+    ClkOut = 0x19,
+    WUser = 0x20,
+    ChangeBaudRate = 0x21,
+    Exit = 0x22,
+    SetStartAddress = 0x23,
+}
+
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Command::Ping),
+            0x03 => Ok(Command::Info),
+            0x04 => Ok(Command::ID),
+            0x05 => Ok(Command::Reset),
+            0x06 => Ok(Command::ErasePage),
+            0x07 => Ok(Command::WritePage),
+            0x08 => Ok(Command::XEBlock),
+            0x09 => Ok(Command::XWPage),
+            0x10 => Ok(Command::Crcx),
+            0x11 => Ok(Command::ReadRange),
+            0x12 => Ok(Command::XRRange),
+            0x13 => Ok(Command::SetAttribute),
+            0x14 => Ok(Command::GetAttribute),
+            0x15 => Ok(Command::CRCInternalFlash),
+            0x16 => Ok(Command::Crcef),
+            0x17 => Ok(Command::XEPage),
+            0x18 => Ok(Command::XFinit),
+            0x19 => Ok(Command::ClkOut),
+            0x20 => Ok(Command::WUser),
+            0x21 => Ok(Command::ChangeBaudRate),
+            0x22 => Ok(Command::Exit),
+            0x23 => Ok(Command::SetStartAddress),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Response {
+    // Responses from the bootloader
+    Overflow = 0x10,
+    Pong = 0x11,
+    BadAddr = 0x12,
+    IntError = 0x13,
+    BadArgs = 0x14,
+    OK = 0x15,
+    Unknown = 0x16,
+    XFTimeout = 0x17,
+    Xfepe = 0x18,
+    Crcrx = 0x19,
+    ReadRange = 0x20,
+    XRRange = 0x21,
+    GetAttribute = 0x22,
+    CRCInternalFlash = 0x23,
+    Crcxf = 0x24,
+    Info = 0x25,
+    ChangeBaudFail = 0x26,
+    BadResp,
+}
+
+impl From<u8> for Response {
+    fn from(value: u8) -> Self {
+        match value {
+            0x10 => Response::Overflow,
+            0x11 => Response::Pong,
+            0x12 => Response::BadAddr,
+            0x13 => Response::IntError,
+            0x14 => Response::BadArgs,
+            0x15 => Response::OK,
+            0x16 => Response::Unknown,
+            0x17 => Response::XFTimeout,
+            0x18 => Response::Xfepe,
+            0x19 => Response::Crcrx,
+            0x20 => Response::ReadRange,
+            0x21 => Response::XRRange,
+            0x22 => Response::GetAttribute,
+            0x23 => Response::CRCInternalFlash,
+            0x24 => Response::Crcxf,
+            0x25 => Response::Info,
+            0x26 => Response::ChangeBaudFail,
+
+            // This error handling is temmporary
+            //TODO(Micu Ana): Add error handling
+            _ => Response::BadResp,
+        }
+    }
+}
+
+pub async fn toggle_bootloader_entry_dtr_rts(
+    port: &mut SerialStream,
+) -> Result<(), TockloaderError> {
+    port.write_data_terminal_ready(true)
+        .map_err(TockloaderError::SerialInitializationError)?;
+    port.write_request_to_send(true)
+        .map_err(TockloaderError::SerialInitializationError)?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    port.write_data_terminal_ready(false)
+        .map_err(TockloaderError::SerialInitializationError)?;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    port.write_request_to_send(false)
+        .map_err(TockloaderError::SerialInitializationError)?;
+
+    Ok(())
+}
+
+/// Error matching a response code that is not the expected one.
+fn response_error(code: u8, expected: Response) -> BootloaderError {
+    match Response::from(code) {
+        Response::Overflow => BootloaderError::Overflow,
+        Response::BadAddr => BootloaderError::BadAddress,
+        Response::IntError => BootloaderError::InternalError,
+        Response::BadArgs => BootloaderError::BadArguments,
+        Response::Unknown => BootloaderError::UnknownCommand,
+        Response::XFTimeout => BootloaderError::ExternalFlashTimeout,
+        Response::Xfepe => BootloaderError::ExternalFlashPageError,
+        Response::ChangeBaudFail => BootloaderError::ChangeBaudRateFailed,
+        Response::BadResp => BootloaderError::UnknownResponse(code),
+        Response::Pong
+        | Response::OK
+        | Response::Crcrx
+        | Response::ReadRange
+        | Response::XRRange
+        | Response::GetAttribute
+        | Response::CRCInternalFlash
+        | Response::Crcxf
+        | Response::Info => BootloaderError::UnexpectedResponse {
+            expected: expected as u8,
+            received: code,
+        },
+    }
+}
+
+pub async fn ping_bootloader_and_wait_for_response(
+    port: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<(), TockloaderError> {
+    let mut error = BootloaderError::Timeout(PING_TIMEOUT);
+    for _ in 0..PING_ATTEMPTS {
+        match issue_command(port, Command::Ping, Vec::new(), false, PING_TIMEOUT).await {
+            Ok(_) => return Ok(()),
+            Err(TockloaderError::BootloaderError(e)) if e.is_retryable() => error = e,
+            Err(e) => return Err(e),
+        }
+        resync(port).await?;
+    }
+    Err(error.into())
+}
+
+/// Reset the command buffer of the bootloader and drop whatever is left of the last response.
+pub async fn resync(
+    port: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<(), TockloaderError> {
+    port.write_all(&SYNC_MESSAGE).await?;
+
+    let mut buffer = [0u8; 256];
+    while let Ok(read) = tokio::time::timeout(RESYNC_QUIET_TIME, port.read(&mut buffer)).await {
+        if read? == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Send `command` with `message` as arguments and return the data of the response, waiting at
+/// most `timeout` for it. Commands that are not answered return no data.
+pub async fn issue_command(
+    port: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    command: Command,
+    message: Vec<u8>,
+    sync: bool,
+    timeout: Duration,
+) -> Result<Vec<u8>, TockloaderError> {
+    let expected = expected_response(command, &message);
+    let mut framed = Framed::new(port, HostCodec::default());
+
+    let exchange = async {
+        // If there should be a sync/reset message, send it before the command
+        if sync {
+            framed.feed(CommandFrame::sync()).await?;
+        }
+        framed.send(CommandFrame::new(command, message)).await?;
+
+        let Some((response_code, _)) = expected else {
+            return Ok(Vec::new());
+        };
+        let frame = framed
+            .next()
+            .await
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))??;
+        if frame.code != response_code as u8 {
+            return Err(response_error(frame.code, response_code).into());
+        }
+
+        Ok(frame.data)
+    };
+
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| BootloaderError::Timeout(timeout))?
+}
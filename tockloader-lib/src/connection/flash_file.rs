@@ -0,0 +1,121 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::errors::TockloaderError;
+
+use super::{check_page_size, TockloaderConnection, DEFAULT_PAGE_SIZE};
+
+// Value of erased flash, used to fill a file grown to its configured size.
+const ERASED_BYTE: u8 = 0xFF;
+
+/// Connection to a binary image of the flash of a board instead of the board itself.
+///
+/// Byte 0 of the file is the flash at `base_address`.
+pub struct FlashFileConnection {
+    file: File,
+    base_address: u64,
+    size: u64,
+    page_size: usize,
+}
+
+impl FlashFileConnection {
+    /// Open the image at `path`. If `size` is given, the file is created if needed and extended
+    /// with erased flash if it is shorter, otherwise the file must exist and the image covers all
+    /// of it.
+    pub fn open(
+        path: impl AsRef<Path>,
+        base_address: u64,
+        size: Option<u64>,
+    ) -> Result<FlashFileConnection, TockloaderError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(size.is_some())
+            .truncate(false)
+            .open(path)
+            .map_err(TockloaderError::UnusableFlashFile)?;
+        let file_len = file
+            .metadata()
+            .map_err(TockloaderError::UnusableFlashFile)?
+            .len();
+
+        let mut conn = FlashFileConnection {
+            file,
+            base_address,
+            size: size.unwrap_or(file_len),
+            page_size: DEFAULT_PAGE_SIZE,
+        };
+        if conn.size > file_len {
+            conn.file
+                .seek(SeekFrom::Start(file_len))
+                .and_then(|_| {
+                    conn.file
+                        .write_all(&vec![ERASED_BYTE; (conn.size - file_len) as usize])
+                })
+                .map_err(TockloaderError::UnusableFlashFile)?;
+        }
+
+        Ok(conn)
+    }
+
+    /// Override the default page size of 512 bytes. A flash file has no real pages, this only
+    /// decides how data is padded and split.
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<(), TockloaderError> {
+        self.page_size = check_page_size(page_size)?;
+        Ok(())
+    }
+
+    /// Offset in the file of the `length` bytes at `address`, if they are all in the image.
+    fn offset(&self, address: u64, length: usize) -> Result<u64, TockloaderError> {
+        address
+            .checked_sub(self.base_address)
+            .filter(|offset| offset + length as u64 <= self.size)
+            .ok_or(TockloaderError::AddressOutOfRange { address, length })
+    }
+}
+
+#[async_trait]
+impl TockloaderConnection for FlashFileConnection {
+    async fn read_range(
+        &mut self,
+        address: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, TockloaderError> {
+        let offset = self.offset(address, length)?;
+
+        let mut buf = vec![0u8; length];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    async fn write_range(&mut self, address: u64, data: &[u8]) -> Result<(), TockloaderError> {
+        let offset = self.offset(address, data.len())?;
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    async fn erase_page(&mut self, address: u64) -> Result<(), TockloaderError> {
+        let erased = vec![ERASED_BYTE; self.page_size];
+        self.write_range(address, &erased).await
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    async fn reset(&mut self) -> Result<(), TockloaderError> {
+        // There is nothing to reset
+        Ok(())
+    }
+}
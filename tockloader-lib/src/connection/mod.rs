@@ -0,0 +1,255 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+pub mod flash_file;
+pub mod probe;
+pub mod serial;
+
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use probe_rs::probe::DebugProbeInfo;
+
+use crate::attributes::bootloader_attributes::{ATTRIBUTES_ADDRESS, ATTRIBUTE_LEN};
+use crate::errors::TockloaderError;
+use crate::events::{InstallEvent, Observer};
+use flash_file::FlashFileConnection;
+use probe::ProbeRSConnection;
+use serial::{SerialConnection, DEFAULT_BAUD_RATE};
+
+/// Page size used when the flash of the board does not tell its own.
+pub(crate) const DEFAULT_PAGE_SIZE: usize = 512;
+
+/// Check a page size given by the user or read from the board. Writes are split into pages and
+/// apps are padded to them, so a page size must be a power of two.
+pub(crate) fn check_page_size(page_size: usize) -> Result<usize, TockloaderError> {
+    if page_size.is_power_of_two() {
+        Ok(page_size)
+    } else {
+        Err(TockloaderError::InvalidPageSize(page_size))
+    }
+}
+
+/// Low level access to the flash of a board.
+///
+/// Every library operation (listing, installing, ...) is written against this trait, so any
+/// transport implementing it can be passed to them. Addresses are absolute flash addresses.
+#[async_trait]
+pub trait TockloaderConnection: Send {
+    /// Read `length` bytes starting at `address`.
+    async fn read_range(&mut self, address: u64, length: usize)
+        -> Result<Vec<u8>, TockloaderError>;
+
+    /// Write `data` starting at `address`. The address and the length of the data must be
+    /// multiples of [`page_size`](TockloaderConnection::page_size).
+    async fn write_range(&mut self, address: u64, data: &[u8]) -> Result<(), TockloaderError>;
+
+    /// Write several `(address, data)` ranges, with the same alignment rules as
+    /// [`write_range`](TockloaderConnection::write_range), reporting progress to `observer`.
+    /// Transports able to program everything in one go should override this.
+    async fn write_ranges(
+        &mut self,
+        ranges: &[(u64, Vec<u8>)],
+        observer: &Observer,
+    ) -> Result<(), TockloaderError> {
+        let page_size = self.page_size();
+        let total = ranges
+            .iter()
+            .map(|(_, data)| data.len().div_ceil(page_size))
+            .sum();
+
+        let mut index = 0;
+        for (address, data) in ranges {
+            for (offset, page) in data.chunks(page_size).enumerate() {
+                observer.emit(InstallEvent::WritingPage { index, total });
+                self.write_range(address + (offset * page_size) as u64, page)
+                    .await?;
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase the flash page starting at `address`.
+    async fn erase_page(&mut self, address: u64) -> Result<(), TockloaderError>;
+
+    /// CRC32 of the `length` bytes starting at `address`. By default the range is read back and
+    /// the CRC is computed locally.
+    async fn crc32(&mut self, address: u64, length: usize) -> Result<u32, TockloaderError> {
+        let data = self.read_range(address, length).await?;
+        Ok(crc32fast::hash(&data))
+    }
+
+    /// Overwrite the bootloader attribute slot `index` with the 64 bytes of `slot`. By default
+    /// the flash page holding the slot is read, patched and written back.
+    async fn write_attribute(&mut self, index: usize, slot: &[u8]) -> Result<(), TockloaderError> {
+        let address = ATTRIBUTES_ADDRESS + (index * ATTRIBUTE_LEN) as u64;
+        let page_size = self.page_size() as u64;
+        let page_address = address / page_size * page_size;
+
+        let mut page = self.read_range(page_address, page_size as usize).await?;
+        let offset = (address - page_address) as usize;
+        page[offset..offset + ATTRIBUTE_LEN].copy_from_slice(slot);
+        self.write_range(page_address, &page).await
+    }
+
+    /// Size in bytes of a flash page.
+    fn page_size(&self) -> usize;
+
+    /// Reset the board, leaving the bootloader if it is running.
+    async fn reset(&mut self) -> Result<(), TockloaderError>;
+}
+
+pub enum ConnectionInfo {
+    ProbeInfo(DebugProbeInfo),
+    SerialInfo {
+        port: String,
+        baud_rate: u32,
+        // Assume the bootloader is already active and skip the DTR/RTS toggling.
+        no_bootloader_entry: bool,
+    },
+    FlashFileInfo {
+        path: PathBuf,
+        // Flash address of the first byte of the file.
+        base_address: u64,
+        // Size of the flash, the whole file being used if it is not given.
+        size: Option<u64>,
+    },
+}
+
+impl From<String> for ConnectionInfo {
+    fn from(port: String) -> Self {
+        ConnectionInfo::SerialInfo {
+            port,
+            baud_rate: DEFAULT_BAUD_RATE,
+            no_bootloader_entry: false,
+        }
+    }
+}
+
+pub enum Connection {
+    ProbeRS(ProbeRSConnection),
+    Serial(SerialConnection),
+    FlashFile(FlashFileConnection),
+}
+
+impl Connection {
+    /// Open a connection to a board. `chip` and `core_index` are only used by debug probes, the
+    /// core defaulting to 0.
+    pub async fn open(
+        info: ConnectionInfo,
+        chip: Option<String>,
+        core_index: Option<usize>,
+    ) -> Result<Connection, TockloaderError> {
+        match info {
+            ConnectionInfo::ProbeInfo(probe_info) => {
+                Ok(Connection::ProbeRS(ProbeRSConnection::open(
+                    probe_info,
+                    chip.ok_or(TockloaderError::MissingChip)?,
+                    core_index.unwrap_or(0),
+                )?))
+            }
+            ConnectionInfo::SerialInfo {
+                port,
+                baud_rate,
+                no_bootloader_entry,
+            } => Ok(Connection::Serial(
+                SerialConnection::open(port, baud_rate, no_bootloader_entry).await?,
+            )),
+            ConnectionInfo::FlashFileInfo {
+                path,
+                base_address,
+                size,
+            } => Ok(Connection::FlashFile(FlashFileConnection::open(
+                path,
+                base_address,
+                size,
+            )?)),
+        }
+    }
+
+    /// Override the flash page size detected when the connection was opened.
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<(), TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.set_page_size(page_size),
+            Connection::Serial(conn) => conn.set_page_size(page_size),
+            Connection::FlashFile(conn) => conn.set_page_size(page_size),
+        }
+    }
+}
+
+#[async_trait]
+impl TockloaderConnection for Connection {
+    async fn read_range(
+        &mut self,
+        address: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.read_range(address, length).await,
+            Connection::Serial(conn) => conn.read_range(address, length).await,
+            Connection::FlashFile(conn) => conn.read_range(address, length).await,
+        }
+    }
+
+    async fn write_range(&mut self, address: u64, data: &[u8]) -> Result<(), TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.write_range(address, data).await,
+            Connection::Serial(conn) => conn.write_range(address, data).await,
+            Connection::FlashFile(conn) => conn.write_range(address, data).await,
+        }
+    }
+
+    async fn write_ranges(
+        &mut self,
+        ranges: &[(u64, Vec<u8>)],
+        observer: &Observer,
+    ) -> Result<(), TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.write_ranges(ranges, observer).await,
+            Connection::Serial(conn) => conn.write_ranges(ranges, observer).await,
+            Connection::FlashFile(conn) => conn.write_ranges(ranges, observer).await,
+        }
+    }
+
+    async fn erase_page(&mut self, address: u64) -> Result<(), TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.erase_page(address).await,
+            Connection::Serial(conn) => conn.erase_page(address).await,
+            Connection::FlashFile(conn) => conn.erase_page(address).await,
+        }
+    }
+
+    async fn crc32(&mut self, address: u64, length: usize) -> Result<u32, TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.crc32(address, length).await,
+            Connection::Serial(conn) => conn.crc32(address, length).await,
+            Connection::FlashFile(conn) => conn.crc32(address, length).await,
+        }
+    }
+
+    async fn write_attribute(&mut self, index: usize, slot: &[u8]) -> Result<(), TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.write_attribute(index, slot).await,
+            Connection::Serial(conn) => conn.write_attribute(index, slot).await,
+            Connection::FlashFile(conn) => conn.write_attribute(index, slot).await,
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        match self {
+            Connection::ProbeRS(conn) => conn.page_size(),
+            Connection::Serial(conn) => conn.page_size(),
+            Connection::FlashFile(conn) => conn.page_size(),
+        }
+    }
+
+    async fn reset(&mut self) -> Result<(), TockloaderError> {
+        match self {
+            Connection::ProbeRS(conn) => conn.reset().await,
+            Connection::Serial(conn) => conn.reset().await,
+            Connection::FlashFile(conn) => conn.reset().await,
+        }
+    }
+}
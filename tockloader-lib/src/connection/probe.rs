@@ -0,0 +1,180 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::FairMutex;
+use probe_rs::config::MemoryRegion;
+use probe_rs::flashing::{DownloadOptions, FlashProgress, ProgressEvent};
+use probe_rs::probe::DebugProbeInfo;
+use probe_rs::{MemoryInterface, Permissions, Session, Target};
+
+use crate::errors::TockloaderError;
+use crate::events::{InstallEvent, Observer};
+
+use super::{check_page_size, TockloaderConnection, DEFAULT_PAGE_SIZE};
+
+pub struct ProbeRSConnection {
+    // A probe-rs session can be shared between threads.
+    session: Arc<FairMutex<Session>>,
+    core_index: usize,
+    page_size: usize,
+}
+
+impl ProbeRSConnection {
+    pub fn new(session: Arc<FairMutex<Session>>, core_index: usize) -> ProbeRSConnection {
+        // Used when probe-rs has no flash algorithm for the target
+        let page_size = flash_page_size(session.lock().target())
+            .filter(|page_size| page_size.is_power_of_two())
+            .unwrap_or(DEFAULT_PAGE_SIZE);
+        ProbeRSConnection {
+            session,
+            core_index,
+            page_size,
+        }
+    }
+
+    pub(crate) fn open(
+        probe_info: DebugProbeInfo,
+        chip: String,
+        core_index: usize,
+    ) -> Result<ProbeRSConnection, TockloaderError> {
+        let probe = probe_info
+            .open()
+            .map_err(TockloaderError::ProbeRsInitializationError)?;
+        match probe.attach(chip, Permissions::default()) {
+            Ok(session) => Ok(ProbeRSConnection::new(
+                Arc::new(FairMutex::new(session)),
+                core_index,
+            )),
+            Err(e) => Err(TockloaderError::ProbeRsCommunicationError(e)),
+        }
+    }
+
+    pub fn session(&self) -> Arc<FairMutex<Session>> {
+        Arc::clone(&self.session)
+    }
+
+    /// Override the page size found in the target description.
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<(), TockloaderError> {
+        self.page_size = check_page_size(page_size)?;
+        Ok(())
+    }
+}
+
+/// Page size of the flash algorithm programming the main flash of `target`.
+fn flash_page_size(target: &Target) -> Option<usize> {
+    // Apps live in the main flash, which is the first non-aliased NVM region.
+    let flash_start = target.memory_map.iter().find_map(|region| match region {
+        MemoryRegion::Nvm(nvm) if !nvm.is_alias => Some(nvm.range.start),
+        _ => None,
+    });
+
+    let algorithm = flash_start
+        .and_then(|start| {
+            target
+                .flash_algorithms
+                .iter()
+                .find(|algorithm| algorithm.flash_properties.address_range.contains(&start))
+        })
+        .or_else(|| {
+            target
+                .flash_algorithms
+                .iter()
+                .find(|algorithm| algorithm.default)
+        })
+        .or_else(|| target.flash_algorithms.first())?;
+
+    Some(algorithm.flash_properties.page_size as usize)
+}
+
+#[async_trait]
+impl TockloaderConnection for ProbeRSConnection {
+    async fn read_range(
+        &mut self,
+        address: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, TockloaderError> {
+        let mut session = self.session.lock();
+        let mut core = session
+            .core(self.core_index)
+            .map_err(|e| TockloaderError::CoreAccessError(self.core_index, e))?;
+
+        let mut buf = vec![0u8; length];
+        core.read(address, &mut buf)
+            .map_err(TockloaderError::ProbeRsReadError)?;
+        Ok(buf)
+    }
+
+    async fn write_range(&mut self, address: u64, data: &[u8]) -> Result<(), TockloaderError> {
+        self.write_ranges(&[(address, data.to_vec())], &Observer::default())
+            .await
+    }
+
+    async fn write_ranges(
+        &mut self,
+        ranges: &[(u64, Vec<u8>)],
+        observer: &Observer,
+    ) -> Result<(), TockloaderError> {
+        let page_size = self.page_size;
+        let mut session = self.session.lock();
+
+        // A single loader erases and programs every sector once, instead of once per range
+        let mut loader = session.target().flash_loader();
+        for (address, data) in ranges {
+            loader
+                .add_data(*address, data)
+                .map_err(TockloaderError::ProbeRsWriteError)?;
+        }
+
+        // probe-rs programs whole flash pages, which may not match the page size used here, so
+        // the index is derived from the number of bytes programmed so far.
+        let total = ranges
+            .iter()
+            .map(|(_, data)| data.len().div_ceil(page_size))
+            .sum::<usize>();
+        let programmed_bytes = AtomicUsize::new(0);
+        let observer = observer.clone();
+        let mut options = DownloadOptions::default();
+        options.keep_unwritten_bytes = true;
+        options.progress = Some(FlashProgress::new(move |event| match event {
+            ProgressEvent::SectorErased { .. } => observer.emit(InstallEvent::ErasingPage),
+            ProgressEvent::PageProgrammed { size, .. } => {
+                let programmed = programmed_bytes.fetch_add(size as usize, Ordering::Relaxed);
+                observer.emit(InstallEvent::WritingPage {
+                    index: (programmed / page_size).min(total.saturating_sub(1)),
+                    total,
+                });
+            }
+            _ => {}
+        }));
+
+        // Finally, the data can be programmed
+        loader
+            .commit(&mut session, options)
+            .map_err(TockloaderError::ProbeRsWriteError)
+    }
+
+    async fn erase_page(&mut self, address: u64) -> Result<(), TockloaderError> {
+        // The flash loader erases whole sectors and restores the bytes around the page, so
+        // writing an erased page is enough.
+        let erased = vec![0xFF; self.page_size()];
+        self.write_range(address, &erased).await
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    async fn reset(&mut self) -> Result<(), TockloaderError> {
+        let mut session = self.session.lock();
+        let mut core = session
+            .core(self.core_index)
+            .map_err(|e| TockloaderError::CoreAccessError(self.core_index, e))?;
+        core.reset()
+            .map_err(TockloaderError::ProbeRsCommunicationError)
+    }
+}
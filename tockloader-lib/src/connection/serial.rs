@@ -0,0 +1,340 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio_serial::{FlowControl, Parity, SerialPort, SerialStream, StopBits};
+
+use crate::attributes::bootloader_attributes::{Attributes, ATTRIBUTES_ADDRESS, ATTRIBUTE_LEN};
+use crate::bootloader_serial::{
+    issue_command, ping_bootloader_and_wait_for_response, resync, toggle_bootloader_entry_dtr_rts,
+    Command, BAUD_RATE_CONFIRM, BAUD_RATE_REQUEST, ESCAPE_CHAR,
+};
+use crate::errors::{BootloaderError, TockloaderError};
+use crate::events::{InstallEvent, Observer};
+
+use super::{check_page_size, TockloaderConnection, DEFAULT_PAGE_SIZE};
+
+/// Baud rate the bootloader listens at after reset.
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+
+// The bootloader can not send back more than this in a single ReadRange response.
+const MAX_READ_LENGTH: usize = 4095;
+
+// How long the bootloader has to answer a command.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How many times a command is sent again after a lost or garbled response, or after the
+// bootloader reports that its buffer overflowed.
+const DEFAULT_RETRIES: usize = 3;
+
+/// Connection to the Tock bootloader. The port is a serial port unless another stream speaking the
+/// bootloader protocol is given, such as a simulated bootloader.
+pub struct SerialConnection<P = SerialStream> {
+    // The serial port is awaited on by every bootloader command, so it is kept behind an async
+    // mutex.
+    port: Arc<Mutex<P>>,
+    page_size: usize,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl SerialConnection {
+    /// Open `port` and connect to the bootloader, switching to `baud_rate` if the bootloader
+    /// supports it.
+    pub(crate) async fn open(
+        port: String,
+        baud_rate: u32,
+        no_bootloader_entry: bool,
+    ) -> Result<SerialConnection, TockloaderError> {
+        let builder = tokio_serial::new(port, DEFAULT_BAUD_RATE)
+            .parity(Parity::None)
+            .stop_bits(StopBits::One)
+            .flow_control(FlowControl::None)
+            .timeout(Duration::from_millis(500));
+        let mut stream =
+            SerialStream::open(&builder).map_err(TockloaderError::SerialInitializationError)?;
+
+        if !no_bootloader_entry {
+            toggle_bootloader_entry_dtr_rts(&mut stream).await?;
+        }
+
+        let mut conn = SerialConnection::connect(stream).await?;
+        if baud_rate != DEFAULT_BAUD_RATE {
+            conn.change_baud_rate(baud_rate).await?;
+        }
+        Ok(conn)
+    }
+}
+
+/// A port whose baud rate can be changed, which the connection needs to switch to a faster baud
+/// rate.
+pub trait SwitchBaudRate {
+    fn switch_baud_rate(&mut self, baud_rate: u32) -> Result<(), TockloaderError>;
+}
+
+impl SwitchBaudRate for SerialStream {
+    fn switch_baud_rate(&mut self, baud_rate: u32) -> Result<(), TockloaderError> {
+        self.set_baud_rate(baud_rate)
+            .map_err(TockloaderError::SerialInitializationError)
+    }
+}
+
+impl<P: AsyncRead + AsyncWrite + SwitchBaudRate + Unpin + Send> SerialConnection<P> {
+    /// Switch the bootloader and the port to `baud_rate`, and return the baud rate used from now
+    /// on. If the bootloader does not switch, because it refuses the new baud rate, does not know
+    /// the command or can not be reached, both ends stay at or go back to 115200 baud.
+    pub async fn change_baud_rate(&mut self, baud_rate: u32) -> Result<u32, TockloaderError> {
+        let mut pkt = vec![BAUD_RATE_REQUEST];
+        pkt.extend_from_slice(&baud_rate.to_le_bytes());
+        if let Err(e) = self.issue(Command::ChangeBaudRate, pkt).await {
+            log::warn!(
+                "Bootloader did not switch to {} baud ({}), using {} baud.",
+                baud_rate,
+                e,
+                DEFAULT_BAUD_RATE
+            );
+            resync(&mut *self.port.lock().await).await?;
+            return Ok(DEFAULT_BAUD_RATE);
+        }
+
+        // The bootloader now listens at the new baud rate and waits for a confirmation with it
+        let mut port = self.port.lock().await;
+        port.switch_baud_rate(baud_rate)?;
+
+        let mut pkt = vec![BAUD_RATE_CONFIRM];
+        pkt.extend_from_slice(&baud_rate.to_le_bytes());
+        if let Err(e) = issue_command(
+            &mut *port,
+            Command::ChangeBaudRate,
+            pkt,
+            false,
+            self.timeout,
+        )
+        .await
+        {
+            // Without the confirmation, the bootloader goes back to the default baud rate
+            log::warn!(
+                "Could not confirm {} baud ({}), using {} baud.",
+                baud_rate,
+                e,
+                DEFAULT_BAUD_RATE
+            );
+            port.switch_baud_rate(DEFAULT_BAUD_RATE)?;
+            resync(&mut *port).await?;
+            return Ok(DEFAULT_BAUD_RATE);
+        }
+
+        log::debug!("Switched to {} baud.", baud_rate);
+        Ok(baud_rate)
+    }
+}
+
+impl<P: AsyncRead + AsyncWrite + Unpin + Send> SerialConnection<P> {
+    pub fn new(port: Arc<Mutex<P>>) -> SerialConnection<P> {
+        SerialConnection {
+            port,
+            page_size: DEFAULT_PAGE_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Connect to a bootloader that is already running on the other end of `port`.
+    pub async fn connect(mut port: P) -> Result<SerialConnection<P>, TockloaderError> {
+        // Make sure the bootloader is listening before handing out the connection
+        ping_bootloader_and_wait_for_response(&mut port).await?;
+
+        let mut conn = SerialConnection::new(Arc::new(Mutex::new(port)));
+        if let Some(page_size) = conn.read_page_size_attribute().await? {
+            match check_page_size(page_size) {
+                Ok(page_size) => conn.page_size = page_size,
+                Err(e) => log::warn!("Ignoring the page size attribute: {}", e),
+            }
+        }
+
+        Ok(conn)
+    }
+
+    pub fn port(&self) -> Arc<Mutex<P>> {
+        Arc::clone(&self.port)
+    }
+
+    /// Override the page size reported by the bootloader.
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<(), TockloaderError> {
+        self.page_size = check_page_size(page_size)?;
+        Ok(())
+    }
+
+    /// Set how long the bootloader has to answer each command.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set how many times a command is sent again when its response is lost or garbled, or when
+    /// the bootloader could not keep up with the data.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Issue a command and return the data of its response, resynchronising with the bootloader
+    /// before each new attempt.
+    async fn issue(&self, command: Command, message: Vec<u8>) -> Result<Vec<u8>, TockloaderError> {
+        let mut port = self.port.lock().await;
+
+        let mut attempt = 0;
+        loop {
+            let result =
+                issue_command(&mut *port, command, message.clone(), true, self.timeout).await;
+            match result {
+                Ok(data) => return Ok(data),
+                Err(TockloaderError::BootloaderError(e))
+                    if e.is_retryable() && attempt < self.retries =>
+                {
+                    log::debug!("Sending command again after error: {}", e);
+                    attempt += 1;
+                    resync(&mut *port).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Issue a command that only expects an OK back.
+    async fn issue_write_command(
+        &self,
+        command: Command,
+        address: u64,
+        message: Vec<u8>,
+    ) -> Result<(), TockloaderError> {
+        match self.issue(command, message).await {
+            Err(TockloaderError::BootloaderError(BootloaderError::BadAddress)) => {
+                Err(TockloaderError::BootloaderBadAddress(address))
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Boards whose flash pages are not 512 bytes long store their page size in the "pagesize"
+    /// bootloader attribute, either in decimal or in hex.
+    async fn read_page_size_attribute(&mut self) -> Result<Option<usize>, TockloaderError> {
+        let attributes = Attributes::read(self).await?;
+
+        Ok(attributes
+            .get("pagesize")
+            .and_then(|value| match value.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16).ok(),
+                None => value.parse().ok(),
+            }))
+    }
+}
+
+#[async_trait]
+impl<P: AsyncRead + AsyncWrite + Unpin + Send> TockloaderConnection for SerialConnection<P> {
+    async fn read_range(
+        &mut self,
+        address: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, TockloaderError> {
+        let mut data = Vec::with_capacity(length);
+
+        while data.len() < length {
+            let chunk_length = (length - data.len()).min(MAX_READ_LENGTH);
+
+            let mut pkt = ((address as usize + data.len()) as u32)
+                .to_le_bytes()
+                .to_vec();
+            pkt.extend_from_slice(&(chunk_length as u16).to_le_bytes());
+
+            let chunk = self.issue(Command::ReadRange, pkt).await?;
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(data)
+    }
+
+    async fn write_range(&mut self, address: u64, data: &[u8]) -> Result<(), TockloaderError> {
+        self.write_ranges(&[(address, data.to_vec())], &Observer::default())
+            .await
+    }
+
+    async fn write_ranges(
+        &mut self,
+        ranges: &[(u64, Vec<u8>)],
+        observer: &Observer,
+    ) -> Result<(), TockloaderError> {
+        let page_size = self.page_size;
+        let total = ranges
+            .iter()
+            .map(|(_, data)| data.len().div_ceil(page_size))
+            .sum();
+
+        let mut index = 0;
+        for (address, data) in ranges {
+            for (offset, chunk) in data.chunks(page_size).enumerate() {
+                let page_address = address + (offset * page_size) as u64;
+                observer.emit(InstallEvent::ErasingPage);
+                self.erase_page(page_address).await?;
+
+                // The bootloader always writes whole pages
+                observer.emit(InstallEvent::WritingPage { index, total });
+                let mut pkt = (page_address as u32).to_le_bytes().to_vec();
+                pkt.extend_from_slice(chunk);
+                pkt.resize(4 + page_size, 0xFF);
+
+                self.issue_write_command(Command::WritePage, page_address, pkt)
+                    .await?;
+                index += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn erase_page(&mut self, address: u64) -> Result<(), TockloaderError> {
+        let pkt = (address as u32).to_le_bytes().to_vec();
+        self.issue_write_command(Command::ErasePage, address, pkt)
+            .await
+    }
+
+    async fn crc32(&mut self, address: u64, length: usize) -> Result<u32, TockloaderError> {
+        // Let the bootloader compute the CRC instead of sending the whole range over the wire
+        let mut pkt = (address as u32).to_le_bytes().to_vec();
+        pkt.extend_from_slice(&(length as u32).to_le_bytes());
+
+        let crc = self.issue(Command::CRCInternalFlash, pkt).await?;
+
+        Ok(u32::from_le_bytes(
+            crc.as_slice()
+                .try_into()
+                .map_err(|_| BootloaderError::Garbled(crc.clone()))?,
+        ))
+    }
+
+    async fn write_attribute(&mut self, index: usize, slot: &[u8]) -> Result<(), TockloaderError> {
+        // The bootloader knows where its attributes live, so only the slot index is sent
+        let mut pkt = vec![index as u8];
+        pkt.extend_from_slice(slot);
+
+        let address = ATTRIBUTES_ADDRESS + (index * ATTRIBUTE_LEN) as u64;
+        self.issue_write_command(Command::SetAttribute, address, pkt)
+            .await
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    async fn reset(&mut self) -> Result<(), TockloaderError> {
+        // The bootloader resets the board as soon as it receives the command, so there is no
+        // response to wait for.
+        let mut port = self.port.lock().await;
+        port.write_all(&[ESCAPE_CHAR, Command::Exit as u8]).await?;
+        Ok(())
+    }
+}
@@ -3,9 +3,12 @@
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
 use std::io;
+use std::time::Duration;
 
 use thiserror::Error;
 
+use crate::attributes::kernel_attributes::KernelAttributesError;
+
 #[derive(Debug, Error)]
 pub enum TockloaderError {
     #[error("Error occurred while trying to access core: {0}")]
@@ -27,11 +30,32 @@ pub enum TockloaderError {
     SerialInitializationError(#[from] tokio_serial::Error),
 
     #[error("Bootloader did not respond properly: {0}")]
-    BootloaderError(u8),
+    BootloaderError(#[from] BootloaderError),
+
+    #[error("Bootloader rejected address {0:#x}.")]
+    BootloaderBadAddress(u64),
+
+    #[error("A chip must be specified to open a debug probe connection.")]
+    MissingChip,
+
+    #[error("Tab is only compatible with {tab_boards:?}, not with {board}. Use force to install it anyway.")]
+    IncompatibleBoard {
+        tab_boards: Vec<String>,
+        board: String,
+    },
+
+    #[error("App requires Tock {}.{}, but the board runs Tock {}.{} or newer. Use force to install it anyway.", required.0, required.1, kernel_minimum.0, kernel_minimum.1)]
+    IncompatibleKernel {
+        required: (u16, u16),
+        kernel_minimum: (u16, u16),
+    },
 
     #[error("No binary found for {0} architecture.")]
     NoBinaryError(String),
 
+    #[error("No binary for {arch} architecture can be placed in the free flash starting at {free_address:#x}.")]
+    NoSuitableBinary { arch: String, free_address: u64 },
+
     #[error("App data could not be parsed.")]
     ParsingError(tbf_parser::types::TbfParseError),
 
@@ -44,9 +68,111 @@ pub enum TockloaderError {
     #[error("Failed to use tab from provided path. Inner: {0}")]
     UnusableTab(io::Error),
 
+    #[error("Failed to read TBF from provided path. Inner: {0}")]
+    UnusableTbf(io::Error),
+
+    #[error("Failed to use flash file from provided path. Inner: {0}")]
+    UnusableFlashFile(io::Error),
+
+    #[error("Invalid page size {0}, it must be a power of two.")]
+    InvalidPageSize(usize),
+
+    #[error("Range of {length} bytes at {address:#x} is outside of the flash.")]
+    AddressOutOfRange { address: u64, length: usize },
+
     #[error("Failed to parse metadata. Inner: {0}")]
     InvalidMetadata(toml::de::Error),
 
     #[error("No metadata.toml found.")]
     NoMetadata,
+
+    #[error("Flash at {address:#x} does not match what was written: expected CRC {expected:#010x}, found {actual:#010x}.")]
+    VerificationFailed {
+        address: u64,
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("Invalid kernel attributes. Inner: {0}")]
+    InvalidKernelAttributes(KernelAttributesError),
+
+    #[error("Bootloader attribute slot {index} is not valid UTF-8: {raw:02x?}")]
+    InvalidAttributeEncoding { index: usize, raw: Vec<u8> },
+
+    #[error("Bootloader version is not valid UTF-8: {0:02x?}")]
+    InvalidBootloaderVersion(Vec<u8>),
+
+    #[error("Invalid bootloader attribute: {0}")]
+    InvalidAttribute(String),
+
+    #[error("No free bootloader attribute slot left for {0}.")]
+    AttributeTableFull(String),
+
+    #[error(
+        "App must be installed at {address:#x}, but the flash is only free from {free_address:#x}."
+    )]
+    FixedAddressUnavailable { address: u64, free_address: u64 },
+
+    #[error("Binary is not a valid TBF.")]
+    InvalidTbf,
+
+    #[error("No app named {0} is installed.")]
+    AppNotFound(String),
+
+    #[error("App {0} is sticky, it can only be uninstalled with force.")]
+    StickyApp(String),
+}
+
+/// Failure of a command sent to the serial bootloader.
+#[derive(Debug, Error)]
+pub enum BootloaderError {
+    #[error("No response received within {0:?}.")]
+    Timeout(Duration),
+
+    #[error("Garbled response: {0:02x?}")]
+    Garbled(Vec<u8>),
+
+    #[error("Unknown response code {0:#04x}.")]
+    UnknownResponse(u8),
+
+    #[error("Expected response {expected:#04x}, received {received:#04x}.")]
+    UnexpectedResponse { expected: u8, received: u8 },
+
+    #[error("Bootloader buffer overflowed.")]
+    Overflow,
+
+    #[error("Bootloader rejected the address.")]
+    BadAddress,
+
+    #[error("Bootloader internal error.")]
+    InternalError,
+
+    #[error("Bootloader rejected the arguments of the command.")]
+    BadArguments,
+
+    #[error("Bootloader does not know the command.")]
+    UnknownCommand,
+
+    #[error("External flash timed out.")]
+    ExternalFlashTimeout,
+
+    #[error("External flash page error.")]
+    ExternalFlashPageError,
+
+    #[error("Bootloader could not change the baud rate.")]
+    ChangeBaudRateFailed,
+}
+
+impl BootloaderError {
+    /// Whether sending the command again after resynchronising may succeed.
+    pub(crate) fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            BootloaderError::Timeout(_)
+                | BootloaderError::Garbled(_)
+                | BootloaderError::UnknownResponse(_)
+                | BootloaderError::UnexpectedResponse { .. }
+                | BootloaderError::Overflow
+        )
+    }
 }
@@ -0,0 +1,62 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Progress of a library operation.
+///
+/// Listing apps and reading board information emit [`ReadingApp`](InstallEvent::ReadingApp) for
+/// each installed app, then [`Done`](InstallEvent::Done).
+///
+/// Installing emits [`WritingPage`](InstallEvent::WritingPage) for each page written,
+/// [`Verifying`](InstallEvent::Verifying) once they are all written, then `Done`. Serial
+/// connections also emit [`ErasingPage`](InstallEvent::ErasingPage) before each page and debug
+/// probes after each erased sector, while flash files are written without erasing and never emit
+/// it.
+#[derive(Clone, Debug)]
+pub enum InstallEvent {
+    /// An installed app is being read from flash.
+    ReadingApp { address: u64 },
+    /// A flash page (or sector, for debug probes) has been erased.
+    ErasingPage,
+    /// Page `index` out of `total` is being written.
+    WritingPage { index: usize, total: usize },
+    /// The written flash is being compared against what was sent.
+    Verifying,
+    /// The operation finished successfully.
+    Done,
+}
+
+/// Receives the [`InstallEvent`]s of a library operation.
+///
+/// Events are also logged at the debug level, so the default observer, which ignores them, only
+/// relies on the `log` facade. Cloning an observer is cheap, the callback is shared.
+#[derive(Clone, Default)]
+pub struct Observer {
+    callback: Option<Arc<dyn Fn(InstallEvent) + Send + Sync>>,
+}
+
+impl Observer {
+    pub fn new(callback: impl Fn(InstallEvent) + Send + Sync + 'static) -> Observer {
+        Observer {
+            callback: Some(Arc::new(callback)),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: InstallEvent) {
+        log::debug!("{:?}", event);
+        if let Some(callback) = &self.callback {
+            callback(event);
+        }
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observer")
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
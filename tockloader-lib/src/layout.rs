@@ -0,0 +1,234 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! Planning of where every app goes when the whole app region is written again.
+//!
+//! Apps compiled for a fixed address and installed sticky apps are pinned: they stay where they
+//! are. The other apps are then placed biggest first, each at the lowest address where it fits
+//! between the pinned ones. On Cortex-M, the MPU can only protect a region aligned to its size,
+//! rounded up to a power of two, and with power of two sizes this order leaves no gaps.
+//! Remaining gaps are filled with padding apps when the region is written.
+
+use crate::app_list::{next_app_address, InstalledApp};
+use crate::errors::TockloaderError;
+use crate::parse_app_header;
+use crate::tbf_header::{fixed_tbf_address, BASE_HEADER_LEN};
+
+// TBFs are made of 4 byte words, so apps are at least aligned to that.
+const WORD_ALIGNMENT: u64 = 4;
+
+/// An app to place in the app region.
+pub(crate) struct LayoutApp {
+    pub binary: Vec<u8>,
+    /// Address the app can not be moved from.
+    pub pinned_address: Option<u64>,
+}
+
+impl LayoutApp {
+    /// An app to install, pinned if it was compiled for a fixed address.
+    pub fn new(binary: Vec<u8>) -> Result<LayoutApp, TockloaderError> {
+        let header = parse_app_header(&binary)?;
+        Ok(LayoutApp {
+            pinned_address: fixed_tbf_address(&header),
+            binary,
+        })
+    }
+
+    /// An app already on the board. Sticky apps are kept where they are.
+    pub fn installed(app: InstalledApp) -> LayoutApp {
+        let pinned_address =
+            fixed_tbf_address(&app.header).or_else(|| app.header.sticky().then_some(app.address));
+        LayoutApp {
+            binary: app.binary,
+            pinned_address,
+        }
+    }
+}
+
+/// Whether apps of `arch` have to be aligned to their size rounded up to a power of two. This is
+/// assumed when the architecture is not known.
+pub(crate) fn needs_mpu_alignment(arch: Option<&str>) -> bool {
+    arch.is_none_or(|arch| arch.starts_with("cortex-m"))
+}
+
+/// Alignment of an app of `size` bytes.
+pub(crate) fn alignment(size: u64, mpu_alignment: bool) -> u64 {
+    if mpu_alignment {
+        size.next_power_of_two()
+    } else {
+        WORD_ALIGNMENT
+    }
+}
+
+/// Compute the address of every app from `appaddr` on. Returns `(address, binary)` pairs sorted by
+/// address.
+pub(crate) fn plan_layout(
+    appaddr: u64,
+    apps: Vec<LayoutApp>,
+    mpu_alignment: bool,
+) -> Result<Vec<(u64, Vec<u8>)>, TockloaderError> {
+    let (pinned, mut movable): (Vec<_>, Vec<_>) = apps
+        .into_iter()
+        .partition(|app| app.pinned_address.is_some());
+
+    let mut placed: Vec<(u64, Vec<u8>)> = pinned
+        .into_iter()
+        .filter_map(|app| Some((app.pinned_address?, app.binary)))
+        .collect();
+    placed.sort_by_key(|(address, _)| *address);
+
+    // Pinned apps must not overlap, and the gaps between them must be able to hold a padding app
+    let mut end = appaddr;
+    for (address, binary) in &placed {
+        if *address < end || (*address != end && address - end < BASE_HEADER_LEN as u64) {
+            return Err(TockloaderError::FixedAddressUnavailable {
+                address: *address,
+                free_address: end,
+            });
+        }
+        end = address + binary.len() as u64;
+    }
+
+    movable.sort_by_key(|app| std::cmp::Reverse(app.binary.len()));
+    for app in movable {
+        let size = app.binary.len() as u64;
+        let address = first_fit(appaddr, &placed, size, alignment(size, mpu_alignment));
+        let index = placed.partition_point(|(placed_address, _)| *placed_address < address);
+        placed.insert(index, (address, app.binary));
+    }
+
+    Ok(placed)
+}
+
+/// Lowest address where an app of `size` bytes fits between the `placed` apps.
+fn first_fit(appaddr: u64, placed: &[(u64, Vec<u8>)], size: u64, alignment: u64) -> u64 {
+    let mut free_start = appaddr;
+    for (address, binary) in placed {
+        let candidate = next_app_address(free_start, alignment);
+        let candidate_end = candidate + size;
+        // What is left before the next app has to be empty or big enough for a padding app
+        if candidate_end == *address
+            || (candidate_end < *address && address - candidate_end >= BASE_HEADER_LEN as u64)
+        {
+            return candidate;
+        }
+        free_start = free_start.max(address + binary.len() as u64);
+    }
+    next_app_address(free_start, alignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tbf_header::{TbfBuilder, FLAG_ENABLED, FLAG_STICKY};
+
+    const APPADDR: u64 = 0x10000;
+
+    fn app(size: usize) -> LayoutApp {
+        LayoutApp {
+            binary: vec![0; size],
+            pinned_address: None,
+        }
+    }
+
+    fn pinned(size: usize, address: u64) -> LayoutApp {
+        LayoutApp {
+            binary: vec![0; size],
+            pinned_address: Some(address),
+        }
+    }
+
+    /// An installed app of `size` bytes at `address` with a main TLV and the given `flags`.
+    fn installed(size: u32, address: u64, flags: u32) -> LayoutApp {
+        let binary = TbfBuilder::new(size).with_flags(flags).build();
+        LayoutApp::installed(InstalledApp {
+            address,
+            header: parse_app_header(&binary).unwrap(),
+            binary,
+        })
+    }
+
+    /// `(address, size)` of every app placed.
+    fn plan(appaddr: u64, apps: Vec<LayoutApp>, mpu_alignment: bool) -> Vec<(u64, usize)> {
+        plan_layout(appaddr, apps, mpu_alignment)
+            .unwrap()
+            .iter()
+            .map(|(address, binary)| (*address, binary.len()))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_pinned_apps_in_place() {
+        let sticky = installed(0x1000, APPADDR, FLAG_ENABLED | FLAG_STICKY);
+        assert_eq!(sticky.pinned_address, Some(APPADDR));
+        assert_eq!(
+            installed(0x1000, 0x18000, FLAG_ENABLED).pinned_address,
+            None
+        );
+
+        let apps = vec![app(0x1000), pinned(0x2000, 0x14000), app(0x2000), sticky];
+        assert_eq!(
+            plan(APPADDR, apps, true),
+            [
+                (0x10000, 0x1000),
+                (0x11000, 0x1000),
+                (0x12000, 0x2000),
+                (0x14000, 0x2000)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_pinned_apps() {
+        for second in [0x11000, 0x12008] {
+            let apps = vec![pinned(0x2000, 0x10000), pinned(0x1000, second)];
+            assert!(matches!(
+                plan_layout(APPADDR, apps, true),
+                Err(TockloaderError::FixedAddressUnavailable {
+                    address,
+                    free_address: 0x12000,
+                }) if address == second
+            ));
+        }
+
+        assert!(matches!(
+            plan_layout(APPADDR, vec![pinned(0x1000, APPADDR - 0x1000)], true),
+            Err(TockloaderError::FixedAddressUnavailable { .. })
+        ));
+    }
+
+    #[test]
+    fn skips_gaps_too_small_for_padding() {
+        let apps = vec![app(0x100), pinned(0x100, APPADDR + 0x108)];
+        assert_eq!(
+            plan(APPADDR, apps, false),
+            [(0x10108, 0x100), (0x10208, 0x100)]
+        );
+    }
+
+    #[test]
+    fn aligns_apps_to_their_size_on_cortex_m() {
+        assert!(needs_mpu_alignment(Some("cortex-m4")));
+        assert!(needs_mpu_alignment(None));
+
+        let apps = vec![app(0x400), app(0x1000), app(0x300)];
+        assert_eq!(
+            plan(APPADDR + 0x200, apps, true),
+            [(0x10400, 0x400), (0x10800, 0x300), (0x11000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn aligns_apps_to_words_elsewhere() {
+        assert!(!needs_mpu_alignment(Some("rv32imc")));
+
+        let apps = vec![app(0x400), app(0x1000), app(0x304)];
+        assert_eq!(
+            plan(APPADDR, apps, false),
+            [(0x10000, 0x1000), (0x11000, 0x400), (0x11400, 0x304)]
+        );
+        assert_eq!(alignment(0x304, false), 4);
+        assert_eq!(alignment(0x304, true), 0x400);
+    }
+}
@@ -2,28 +2,70 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
+pub(crate) mod app_list;
 pub mod attributes;
 pub(crate) mod bootloader_serial;
 pub mod connection;
 pub mod errors;
+pub mod events;
+pub(crate) mod layout;
+#[cfg(feature = "testing")]
+pub mod simulator;
 pub mod tabs;
-
+pub(crate) mod tbf_header;
+
+use app_list::{
+    next_app_address, read_installed_apps, read_installed_headers, rewrite_apps, InstalledApp,
+    InstalledHeader,
+};
+use attributes::app_attributes::AppAttributes;
+use attributes::bootloader_attributes::{
+    encode_attribute, Attributes, ATTRIBUTES_ADDRESS, ATTRIBUTE_LEN,
+};
+use attributes::general_attributes::GeneralAttributes;
 use attributes::system_attributes::SystemAttributes;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
-use std::sync::Arc;
 
-use connection::Connection;
-use probe_rs::flashing::DownloadOptions;
+use connection::TockloaderConnection;
 use probe_rs::probe::DebugProbeInfo;
-use probe_rs::MemoryInterface;
 
 use errors::TockloaderError;
-use tabs::tab::Tab;
-use tbf_parser::parse::parse_tbf_header_lengths;
+use events::{InstallEvent, Observer};
+use layout::{alignment, needs_mpu_alignment, LayoutApp};
+use tabs::tab::{select_binary, Tab};
+use tbf_header::{
+    fixed_tbf_address, padding_tbf, set_header_flag, BASE_HEADER_LEN, CHECKSUM_OFFSET,
+    FLAGS_OFFSET, FLAG_ENABLED, FLAG_STICKY,
+};
+use tbf_parser::parse::{parse_tbf_header, parse_tbf_header_lengths};
+use tbf_parser::types::TbfHeader;
 use tokio_serial::SerialPortInfo;
 
+/// Options changing how apps are installed.
+#[derive(Clone, Debug)]
+pub struct InstallOptions {
+    /// Replace an installed app with the same name instead of installing a second copy next to
+    /// it.
+    pub replace: bool,
+    /// Install tabs even on boards they are not listed as compatible with.
+    pub force: bool,
+    /// Lay out all the apps again and write the whole app region at once, instead of adding the
+    /// new app after the installed ones.
+    pub bundle: bool,
+}
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        InstallOptions {
+            replace: true,
+            force: false,
+            bundle: false,
+        }
+    }
+}
+
 pub fn list_debug_probes() -> Vec<DebugProbeInfo> {
     probe_rs::probe::list::Lister::new().list_all()
 }
@@ -32,343 +74,698 @@ pub fn list_serial_ports() -> Result<Vec<SerialPortInfo>, TockloaderError> {
     tokio_serial::available_ports().map_err(TockloaderError::SerialInitializationError)
 }
 
-pub fn install_app(
-    choice: Connection,
-    core_index: Option<&usize>,
+pub async fn list(
+    conn: &mut impl TockloaderConnection,
+    observer: &Observer,
+) -> Result<Vec<AppAttributes>, TockloaderError> {
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
+    let appaddr = system_attributes.appaddr;
+
+    let apps_attributes = AppAttributes::read_apps_data(conn, appaddr, observer).await?;
+    observer.emit(InstallEvent::Done);
+
+    Ok(apps_attributes)
+}
+
+pub async fn info(
+    conn: &mut impl TockloaderConnection,
+    observer: &Observer,
+) -> Result<GeneralAttributes, TockloaderError> {
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
+    let appaddr = system_attributes.appaddr;
+
+    let apps_attributes = AppAttributes::read_apps_data(conn, appaddr, observer).await?;
+    observer.emit(InstallEvent::Done);
+
+    Ok(GeneralAttributes::new(system_attributes, apps_attributes))
+}
+
+/// Read the whole bootloader attribute table.
+pub async fn read_attributes(
+    conn: &mut impl TockloaderConnection,
+) -> Result<Attributes, TockloaderError> {
+    Attributes::read(conn).await
+}
+
+/// Set the bootloader attribute `key` to `value`, overwriting its slot if the key already exists
+/// or using the first empty one otherwise.
+pub async fn write_attribute(
+    conn: &mut impl TockloaderConnection,
+    key: &str,
+    value: &str,
+) -> Result<(), TockloaderError> {
+    let slot = encode_attribute(key, value)?;
+    let index = Attributes::read(conn)
+        .await?
+        .slot_for(key)
+        .ok_or(TockloaderError::AttributeTableFull(key.to_owned()))?;
+
+    conn.write_attribute(index, &slot).await?;
+    verify(
+        conn,
+        ATTRIBUTES_ADDRESS + (index * ATTRIBUTE_LEN) as u64,
+        &slot,
+    )
+    .await
+}
+
+pub async fn install_app(
+    conn: &mut impl TockloaderConnection,
     tab_file: Tab,
+    options: &InstallOptions,
+    observer: &Observer,
 ) -> Result<(), TockloaderError> {
-    match choice {
-        Connection::ProbeRS(session) => {
-            let session = Arc::clone(&session);
-            let mut session = session.lock();
-            // Get core - if not specified, by default is 0
-            let mut core = session
-                .core(*core_index.unwrap())
-                .map_err(|e| TockloaderError::CoreAccessError(*core_index.unwrap(), e))?;
-            // Get board data
-            let system_attributes = SystemAttributes::read_system_attributes_probe(&mut core)?;
-
-            let board = system_attributes
-                .board
-                .ok_or(TockloaderError::MisconfiguredBoard(
-                    "No board name found.".to_owned(),
-                ))?;
-            let kernel_version =
-                system_attributes
-                    .kernel_version
-                    .ok_or(TockloaderError::MisconfiguredBoard(
-                        "No kernel version found.".to_owned(),
-                    ))?;
-
-            // Verify if the specified app is compatible with board
-            // TODO(Micu Ana): Replace the print with log messages
-            if tab_file.is_compatible_with_board(&board) {
-                println!("Specified tab is compatible with board.");
-            } else {
-                panic!("Specified tab is not compatible with board.");
-            }
+    // Get board data
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
+    let appaddr = system_attributes.appaddr;
+
+    // Apps compiled for a fixed address can only go where the flash is still free, or where the
+    // app they replace is
+    let installed_headers = read_installed_headers(conn, appaddr).await?;
+    let free_address = installed_headers
+        .last()
+        .map_or(appaddr, |installed| installed.end());
+    let binary = tab_binary(
+        &system_attributes,
+        &tab_file,
+        options,
+        free_address,
+        &installed_headers,
+    )?;
+
+    write_app(conn, &system_attributes, binary, options, observer).await?;
+    observer.emit(InstallEvent::Done);
+
+    Ok(())
+}
 
-            // Verify if the specified app is compatible with kernel version
-            // TODO(Micu Ana): Replace the prints with log messages
-            if tab_file.is_compatible_with_kernel_verison(kernel_version as u32) {
-                println!("Specified tab is compatible with your kernel version.");
-            } else {
-                println!("Specified tab is not compatible with your kernel version.");
-            }
+/// Install several tabs at once. The installed apps and the new ones are laid out together and
+/// the whole app region is written in one pass, as with [`InstallOptions::bundle`].
+pub async fn install_apps(
+    conn: &mut impl TockloaderConnection,
+    tab_files: Vec<Tab>,
+    options: &InstallOptions,
+    observer: &Observer,
+) -> Result<(), TockloaderError> {
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
+    let appaddr = system_attributes.appaddr;
 
-            // Get the address from which we start writing the new app
-            // TODO: change appaddr to 32 bit
-            // TODO for the future: support 64 bit arhitecture
-            let mut address =
-                system_attributes
-                    .appaddr
-                    .ok_or(TockloaderError::MisconfiguredBoard(
-                        "No start address found.".to_owned(),
-                    ))?;
-
-            // Loop to check if there are another apps installed
-            loop {
-                // Read a block of 200 8-bit words
-                let mut buff = vec![0u8; 200];
-                core.read(address, &mut buff)
-                    .map_err(TockloaderError::ProbeRsReadError)?;
-
-                let (_ver, _header_len, whole_len) = match parse_tbf_header_lengths(
-                    &buff[0..8]
-                        .try_into()
-                        .expect("Buffer length must be at least 8 bytes long."),
-                ) {
-                    Ok((ver, header_len, whole_len)) if header_len != 0 => {
-                        (ver, header_len, whole_len)
-                    }
-                    _ => break, // No more apps
-                };
-                address += whole_len as u64;
-            }
+    // Every app is placed again, so any fixed address is a candidate
+    let binaries = tab_files
+        .iter()
+        .map(|tab_file| tab_binary(&system_attributes, tab_file, options, appaddr, &[]))
+        .collect::<Result<Vec<_>, _>>()?;
 
-            let arch = system_attributes
-                .arch
-                .ok_or(TockloaderError::MisconfiguredBoard(
-                    "No architecture found.".to_owned(),
-                ))?;
+    bundle_apps(conn, &system_attributes, binaries, options, observer).await?;
+    observer.emit(InstallEvent::Done);
 
-            let mut binary = tab_file.extract_binary(&arch.clone())?; // use the system_attributes arch or the provided one?
+    Ok(())
+}
 
-            let size = binary.len() as u64;
+/// Check that `tab_file` can be installed on the board and pick its binary for the architecture of
+/// the board, the free flash starting at `free_address`. If `options` allow replacing an app of
+/// `installed_headers` with the same name, its address is free as well.
+fn tab_binary(
+    system_attributes: &SystemAttributes,
+    tab_file: &Tab,
+    options: &InstallOptions,
+    free_address: u64,
+    installed_headers: &[InstalledHeader],
+) -> Result<Vec<u8>, TockloaderError> {
+    let board = system_attributes
+        .board
+        .clone()
+        .ok_or(TockloaderError::MisconfiguredBoard(
+            "No board name found.".to_owned(),
+        ))?;
+
+    // Verify if the specified app is compatible with board
+    if tab_file.is_compatible_with_board(&board) {
+        log::info!("Specified tab is compatible with board.");
+    } else if options.force {
+        log::warn!("Specified tab is not compatible with board, installing it anyway.");
+    } else {
+        return Err(TockloaderError::IncompatibleBoard {
+            tab_boards: tab_file.compatible_boards().unwrap_or_default().to_vec(),
+            board,
+        });
+    }
 
-            // Make sure the app is aligned to a multiple of its size
-            let multiple = address / size;
+    let arch = system_attributes
+        .arch
+        .clone()
+        .ok_or(TockloaderError::MisconfiguredBoard(
+            "No architecture found.".to_owned(),
+        ))?;
+
+    let candidates = tab_file.extract_binaries(&arch)?; // use the system_attributes arch or the provided one?
+    let replaced_address = candidates
+        .first()
+        .and_then(|candidate| candidate.header.get_package_name())
+        .filter(|_| options.replace)
+        .and_then(|name| {
+            installed_headers
+                .iter()
+                .find(|installed| installed.header.get_package_name() == Some(name))
+        })
+        .map(|installed| installed.address);
+    let binary = select_binary(candidates, free_address, replaced_address)
+        .ok_or(TockloaderError::NoSuitableBinary {
+            arch: arch.clone(),
+            free_address,
+        })?
+        .data;
+    check_kernel_version(
+        system_attributes,
+        &binary,
+        Some(tab_file.minimum_kernel_version()),
+        options.force,
+    )?;
+
+    Ok(binary)
+}
 
-            let (new_address, _gap_size) = if multiple * size != address {
-                let new_address = ((address + size) / size) * size;
-                let gap_size = new_address - address;
-                (new_address, gap_size)
-            } else {
-                (address, 0)
-            };
+pub async fn install_tbf(
+    conn: &mut impl TockloaderConnection,
+    tbf_file: impl AsRef<Path>,
+    options: &InstallOptions,
+    observer: &Observer,
+) -> Result<(), TockloaderError> {
+    // Get board data
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
 
-            // No more need of core
-            drop(core);
+    let mut binary = vec![];
+    File::open(tbf_file)
+        .and_then(|mut file| file.read_to_end(&mut binary))
+        .map_err(TockloaderError::UnusableTbf)?;
+    check_kernel_version(&system_attributes, &binary, None, options.force)?;
 
-            // Make sure the binary is a multiple of the page size by padding 0xFFs
-            // TODO(Micu Ana): check if the page-size differs
-            let page_size = 512;
-            let needs_padding = binary.len() % page_size != 0;
+    write_app(conn, &system_attributes, binary, options, observer).await?;
+    observer.emit(InstallEvent::Done);
 
-            if needs_padding {
-                let remaining = page_size - (binary.len() % page_size);
-                dbg!(remaining);
-                for _i in 0..remaining {
-                    binary.push(0xFF);
-                }
-            }
+    Ok(())
+}
 
-            // Get indices of pages that have valid data to write
-            let mut valid_pages: Vec<u8> = Vec::new();
-            for i in 0..(size as usize / page_size) {
-                for b in binary[(i * page_size)..((i + 1) * page_size)]
-                    .iter()
-                    .copied()
-                {
-                    if b != 0 {
-                        valid_pages.push(i.try_into().unwrap());
-                        break;
-                    }
-                }
-            }
+/// Remove every app called `name` from the board. Sticky apps are only removed with `force`. The
+/// remaining apps, except sticky and fixed address ones, are moved down to fill the freed space,
+/// and the flash after the last one is erased so it ends the list of apps.
+pub async fn uninstall_app(
+    conn: &mut impl TockloaderConnection,
+    name: &str,
+    force: bool,
+) -> Result<(), TockloaderError> {
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
+    let appaddr = system_attributes.appaddr;
 
-            // If there are no pages valid, all pages would have been removed, so we write them all
-            if valid_pages.is_empty() {
-                for i in 0..(size as usize / page_size) {
-                    valid_pages.push(i.try_into().unwrap());
-                }
-            }
+    let installed_apps = read_installed_apps(conn, appaddr).await?;
+    let old_end = installed_apps.last().map_or(appaddr, |app| app.end());
 
-            // Include a blank page (if exists) after the end of a valid page. There might be a usable 0 on the next page
-            let mut ending_pages: Vec<u8> = Vec::new();
-            for &i in &valid_pages {
-                let mut iter = valid_pages.iter();
-                if !iter.any(|&x| x == (i + 1)) && (i + 1) < (size as usize / page_size) as u8 {
-                    ending_pages.push(i + 1);
+    let (removed, remaining): (Vec<_>, Vec<_>) = installed_apps
+        .into_iter()
+        .partition(|app| app.header.get_package_name() == Some(name));
+    if removed.is_empty() {
+        return Err(TockloaderError::AppNotFound(name.to_owned()));
+    }
+    if !force && removed.iter().any(|app| app.header.sticky()) {
+        return Err(TockloaderError::StickyApp(name.to_owned()));
+    }
+
+    let remaining = remaining
+        .into_iter()
+        .filter(|app| app.header.is_app())
+        .map(LayoutApp::installed)
+        .collect();
+
+    rewrite_apps(
+        conn,
+        appaddr,
+        old_end,
+        remaining,
+        needs_mpu_alignment(system_attributes.arch.as_deref()),
+        &Observer::default(),
+    )
+    .await
+}
+
+/// Enable or disable every app called `name`. Disabled apps stay installed, but are not started
+/// by the kernel.
+pub async fn set_app_enabled(
+    conn: &mut impl TockloaderConnection,
+    name: &str,
+    enabled: bool,
+) -> Result<(), TockloaderError> {
+    update_app_flag(conn, name, FLAG_ENABLED, enabled).await
+}
+
+/// Mark every app called `name` as sticky or not. Sticky apps are only uninstalled with force, and
+/// are not moved when other apps are.
+pub async fn set_app_sticky(
+    conn: &mut impl TockloaderConnection,
+    name: &str,
+    sticky: bool,
+) -> Result<(), TockloaderError> {
+    update_app_flag(conn, name, FLAG_STICKY, sticky).await
+}
+
+async fn update_app_flag(
+    conn: &mut impl TockloaderConnection,
+    name: &str,
+    flag: u32,
+    value: bool,
+) -> Result<(), TockloaderError> {
+    let system_attributes = SystemAttributes::read_system_attributes(conn).await?;
+    let appaddr = system_attributes.appaddr;
+
+    let mut found = false;
+    for app in read_installed_apps(conn, appaddr).await? {
+        if app.header.get_package_name() != Some(name) {
+            continue;
+        }
+        found = true;
+
+        let mut header = app.binary[0..app.header.length() as usize].to_vec();
+        if !set_header_flag(&mut header, flag, value) {
+            continue;
+        }
+
+        // Only the flags and the checksum changed, so only the pages holding them are rewritten.
+        let page_size = conn.page_size() as u64;
+        let first_page = (app.address + FLAGS_OFFSET as u64) / page_size * page_size;
+        let last_page = (app.address + CHECKSUM_OFFSET as u64 + 3) / page_size * page_size;
+        for page_address in (first_page..=last_page).step_by(page_size as usize) {
+            let mut page = conn.read_range(page_address, page_size as usize).await?;
+            for (offset, byte) in page.iter_mut().enumerate() {
+                let address = page_address + offset as u64;
+                if (app.address..app.address + header.len() as u64).contains(&address) {
+                    *byte = header[(address - app.address) as usize];
                 }
             }
+            conn.write_range(page_address, &page).await?;
+            verify(conn, page_address, &page).await?;
+        }
+    }
 
-            for i in ending_pages {
-                valid_pages.push(i);
-            }
+    if found {
+        Ok(())
+    } else {
+        Err(TockloaderError::AppNotFound(name.to_owned()))
+    }
+}
 
-            for i in valid_pages {
-                println!("Writing page number {}", i);
-                // Create the packet that we send to the bootloader
-                // First four bytes are the address of the page
-                let mut pkt = Vec::new();
-
-                // Then the bytes that go into the page
-                for b in binary[(i as usize * page_size)..((i + 1) as usize * page_size)]
-                    .iter()
-                    .copied()
-                {
-                    pkt.push(b);
-                }
-                let mut loader = session.target().flash_loader();
-
-                loader
-                    .add_data(
-                        (new_address as u32 + (i as usize * page_size) as u32).into(),
-                        &pkt,
-                    )
-                    .map_err(TockloaderError::ProbeRsWriteError)?;
-
-                let mut options = DownloadOptions::default();
-                options.keep_unwritten_bytes = true;
-
-                // Finally, the data can be programmed
-                loader
-                    .commit(&mut session, options)
-                    .map_err(TockloaderError::ProbeRsWriteError)?;
+/// Check that the flash starting at `address` holds `expected`, by comparing CRCs.
+pub async fn verify(
+    conn: &mut impl TockloaderConnection,
+    address: u64,
+    expected: &[u8],
+) -> Result<(), TockloaderError> {
+    let expected_crc = crc32fast::hash(expected);
+    let actual_crc = conn.crc32(address, expected.len()).await?;
+
+    if expected_crc == actual_crc {
+        Ok(())
+    } else {
+        Err(TockloaderError::VerificationFailed {
+            address,
+            expected: expected_crc,
+            actual: actual_crc,
+        })
+    }
+}
+
+/// Write `binary` after the last app installed on the board, or over the installed app with the
+/// same name if `options` allow replacing it.
+async fn write_app(
+    conn: &mut impl TockloaderConnection,
+    system_attributes: &SystemAttributes,
+    binary: Vec<u8>,
+    options: &InstallOptions,
+    observer: &Observer,
+) -> Result<(), TockloaderError> {
+    // Get the address from which we start writing the new app
+    // TODO: change appaddr to 32 bit
+    // TODO for the future: support 64 bit arhitecture
+    let appaddr = system_attributes.appaddr;
+
+    if options.bundle {
+        return bundle_apps(conn, system_attributes, vec![binary], options, observer).await;
+    }
+
+    let header = parse_app_header(&binary)?;
+    let fixed_address = fixed_tbf_address(&header);
+
+    // Only the headers are needed to find where the apps end, apps are read in full to be replaced
+    let installed_headers = read_installed_headers(conn, appaddr).await?;
+
+    if options.replace {
+        if let Some(name) = header.get_package_name() {
+            if installed_headers
+                .iter()
+                .any(|installed| installed.header.get_package_name() == Some(name))
+            {
+                let installed_apps = read_installed_apps(conn, appaddr).await?;
+                return replace_app(
+                    conn,
+                    appaddr,
+                    installed_apps,
+                    name,
+                    binary,
+                    needs_mpu_alignment(system_attributes.arch.as_deref()),
+                    observer,
+                )
+                .await;
             }
+        }
+    }
+
+    append_app(
+        conn,
+        installed_headers.last().map_or(appaddr, |app| app.end()),
+        binary,
+        fixed_address,
+        needs_mpu_alignment(system_attributes.arch.as_deref()),
+        observer,
+    )
+    .await
+}
+
+/// Lay out the installed apps and `binaries` again and write the whole app region in one pass.
+/// Installed apps with the same name as a new one are dropped if `options` allow replacing them.
+async fn bundle_apps(
+    conn: &mut impl TockloaderConnection,
+    system_attributes: &SystemAttributes,
+    binaries: Vec<Vec<u8>>,
+    options: &InstallOptions,
+    observer: &Observer,
+) -> Result<(), TockloaderError> {
+    let appaddr = system_attributes.appaddr;
 
-            Ok(())
+    let mut new_names = Vec::new();
+    for binary in &binaries {
+        if let Some(name) = parse_app_header(binary)?.get_package_name() {
+            new_names.push(name.to_owned());
         }
     }
+
+    let installed_apps = read_installed_apps(conn, appaddr).await?;
+    let old_end = installed_apps.last().map_or(appaddr, |app| app.end());
+
+    let mut apps: Vec<LayoutApp> = installed_apps
+        .into_iter()
+        .filter(|app| app.header.is_app())
+        .filter(|app| {
+            !options.replace
+                || !app
+                    .header
+                    .get_package_name()
+                    .is_some_and(|name| new_names.iter().any(|new_name| new_name == name))
+        })
+        .map(LayoutApp::installed)
+        .collect();
+    for binary in binaries {
+        apps.push(LayoutApp::new(binary)?);
+    }
+
+    rewrite_apps(
+        conn,
+        appaddr,
+        old_end,
+        apps,
+        needs_mpu_alignment(system_attributes.arch.as_deref()),
+        observer,
+    )
+    .await
 }
 
-pub fn install_tbf(
-    choice: Connection,
-    core_index: Option<&usize>,
-    tbf_file: impl AsRef<Path>,
+/// Write `binary` at `free_address`, where the installed apps end. If the app was compiled for a
+/// fixed address it is written there instead, with a padding app filling the gap.
+async fn append_app(
+    conn: &mut impl TockloaderConnection,
+    free_address: u64,
+    binary: Vec<u8>,
+    fixed_address: Option<u64>,
+    mpu_alignment: bool,
+    observer: &Observer,
 ) -> Result<(), TockloaderError> {
-    match choice {
-        Connection::ProbeRS(session) => {
-            let session = Arc::clone(&session);
-            let mut session = session.lock();
-            // Get core - if not specified, by default is 0
-            let mut core = session
-                .core(*core_index.unwrap())
-                .map_err(|e| TockloaderError::CoreAccessError(*core_index.unwrap(), e))?;
-            // Get board data
-            let system_attributes = SystemAttributes::read_system_attributes_probe(&mut core)?;
-
-            let board = system_attributes
-                .board
-                .ok_or(TockloaderError::MisconfiguredBoard(
-                    "No board name found.".to_owned(),
-                ))?;
-            let kernel_version =
-                system_attributes
-                    .kernel_version
-                    .ok_or(TockloaderError::MisconfiguredBoard(
-                        "No kernel version found.".to_owned(),
-                    ))?;
- 
-            // Get the address from which we start writing the new app
-            // TODO: change appaddr to 32 bit
-            // TODO for the future: support 64 bit arhitecture
-            let mut address =
-                system_attributes
-                    .appaddr
-                    .ok_or(TockloaderError::MisconfiguredBoard(
-                        "No start address found.".to_owned(),
-                    ))?;
-
-            // Loop to check if there are another apps installed
-            loop {
-                // Read a block of 200 8-bit words
-                let mut buff = vec![0u8; 200];
-                core.read(address, &mut buff)
-                    .map_err(TockloaderError::ProbeRsReadError)?;
-
-                let (_ver, _header_len, whole_len) = match parse_tbf_header_lengths(
-                    &buff[0..8]
-                        .try_into()
-                        .expect("Buffer length must be at least 8 bytes long."),
-                ) {
-                    Ok((ver, header_len, whole_len)) if header_len != 0 => {
-                        (ver, header_len, whole_len)
-                    }
-                    _ => break, // No more apps
-                };
-                address += whole_len as u64;
+    let new_address = match fixed_address {
+        Some(fixed_address) => {
+            // The gap before the app must be able to hold a padding header
+            if fixed_address < free_address
+                || (fixed_address != free_address
+                    && fixed_address - free_address < BASE_HEADER_LEN as u64)
+            {
+                return Err(TockloaderError::FixedAddressUnavailable {
+                    address: fixed_address,
+                    free_address,
+                });
             }
+            fixed_address
+        }
+        None => next_app_address(free_address, alignment(binary.len() as u64, mpu_alignment)),
+    };
+    let gap_size = new_address - free_address;
+
+    // The gap is filled with a padding app so that the kernel can still walk the list of apps. It
+    // starts where the last app ends, which is not necessarily page aligned, so the pages from
+    // there are written along with the padding and the app.
+    let page_size = conn.page_size();
+    let (image_address, mut binary) = if gap_size > 0 || new_address % page_size as u64 != 0 {
+        let image_address = free_address / page_size as u64 * page_size as u64;
+        let mut image = conn
+            .read_range(image_address, (free_address - image_address) as usize)
+            .await?;
+        if gap_size > 0 {
+            image.extend(padding_tbf(gap_size as u32));
+        }
+        image.extend(binary);
+        (image_address, image)
+    } else {
+        (new_address, binary)
+    };
+
+    // Make sure the binary is a multiple of the page size by padding 0xFFs
+    let needs_padding = !binary.len().is_multiple_of(page_size);
+
+    if needs_padding {
+        let remaining = page_size - (binary.len() % page_size);
+        log::debug!("Padding binary with {} bytes", remaining);
+        for _i in 0..remaining {
+            binary.push(0xFF);
+        }
+    }
 
-            let mut binary = vec![];
-            File::open(tbf_file).unwrap().read_to_end(&mut binary).unwrap(); // use the system_attributes arch or the provided one?
+    // Every page is written, the flash under the new app may still hold an older one
+    conn.write_ranges(&[(image_address, binary.clone())], observer)
+        .await?;
+    // The whole image is checked, from the padding before the app to the end of its last page
+    observer.emit(InstallEvent::Verifying);
+    verify(conn, image_address, &binary).await
+}
 
-            let size = binary.len() as u64;
+/// Replace the installed apps called `name` with `binary`. If there is a single copy at least as big
+/// (at the fixed address of the app, if it has one), only its pages are rewritten, otherwise all
+/// the apps are laid out again.
+async fn replace_app(
+    conn: &mut impl TockloaderConnection,
+    appaddr: u64,
+    installed_apps: Vec<InstalledApp>,
+    name: &str,
+    binary: Vec<u8>,
+    mpu_alignment: bool,
+    observer: &Observer,
+) -> Result<(), TockloaderError> {
+    let page_size = conn.page_size() as u64;
+    let fixed_address = fixed_tbf_address(&parse_app_header(&binary)?);
+    let old_end = installed_apps.last().map_or(appaddr, |app| app.end());
+
+    let (old_apps, remaining): (Vec<_>, Vec<_>) = installed_apps
+        .into_iter()
+        .partition(|app| app.header.get_package_name() == Some(name));
+
+    // A smaller app can take the place of the old one, with a padding app after it, if the address
+    // is still aligned for its size
+    if let [old_app] = old_apps.as_slice() {
+        let size = binary.len() as u64;
+        let gap_size = old_app.size().saturating_sub(size);
+        if size <= old_app.size()
+            && (gap_size == 0 || gap_size >= BASE_HEADER_LEN as u64)
+            && old_app.address % alignment(size, mpu_alignment) == 0
+            && fixed_address.is_none_or(|address| address == old_app.address)
+            && old_app.address % page_size == 0
+            && old_app.size() % page_size == 0
+        {
+            let mut image = binary;
+            if gap_size > 0 {
+                image.extend(padding_tbf(gap_size as u32));
+            }
+            conn.write_ranges(&[(old_app.address, image.clone())], observer)
+                .await?;
+            observer.emit(InstallEvent::Verifying);
+            return verify(conn, old_app.address, &image).await;
+        }
+    }
 
-            // Make sure the app is aligned to a multiple of its size
-            let multiple = address / size;
+    let mut apps: Vec<LayoutApp> = remaining
+        .into_iter()
+        .filter(|app| app.header.is_app())
+        .map(LayoutApp::installed)
+        .collect();
+    apps.push(LayoutApp::new(binary)?);
 
-            let (new_address, _gap_size) = if multiple * size != address {
-                let new_address = ((address + size) / size) * size;
-                let gap_size = new_address - address;
-                (new_address, gap_size)
-            } else {
-                (address, 0)
-            };
+    rewrite_apps(conn, appaddr, old_end, apps, mpu_alignment, observer).await
+}
 
-            // No more need of core
-            drop(core);
+/// Check that the kernel of the board can run `binary`.
+///
+/// The version required by the app is taken from the kernel version TLV of its header if there is
+/// one, as it describes this exact binary, or else from the minimum version of the tab it comes
+/// from. A kernel runs apps built for the same major version.
+///
+/// The kernel does not report its version, but kernel attributes only exist since Tock 2.1, so
+/// their presence is a lower bound (the version stored in the attributes is the version of their
+/// format). Apps built for an older major version are rejected. Apps requiring a newer version
+/// than the lower bound, apps without any requirement and boards without kernel attributes can not
+/// be checked, so only a warning is logged.
+fn check_kernel_version(
+    system_attributes: &SystemAttributes,
+    binary: &[u8],
+    tab_minimum: Option<(u16, u16)>,
+    force: bool,
+) -> Result<(), TockloaderError> {
+    let header = parse_app_header(binary)?;
+    let Some(required) = header.get_kernel_version().or(tab_minimum) else {
+        log::warn!("App does not specify the kernel version it requires.");
+        return Ok(());
+    };
+    let Some(kernel_minimum) = system_attributes.minimum_kernel_version() else {
+        log::warn!(
+            "Could not determine the kernel version of the board, app requires Tock {}.{}.",
+            required.0,
+            required.1
+        );
+        return Ok(());
+    };
+
+    if required.0 < kernel_minimum.0 {
+        if !force {
+            return Err(TockloaderError::IncompatibleKernel {
+                required,
+                kernel_minimum,
+            });
+        }
+        log::warn!(
+            "App requires Tock {}.{} but the board runs Tock {}.{} or newer, installing it anyway.",
+            required.0,
+            required.1,
+            kernel_minimum.0,
+            kernel_minimum.1
+        );
+    } else if required > kernel_minimum {
+        log::warn!(
+            "Board runs Tock {}.{} or newer, make sure it runs Tock {}.{} or a newer {}.x as the app requires.",
+            kernel_minimum.0,
+            kernel_minimum.1,
+            required.0,
+            required.1,
+            required.0
+        );
+    }
 
-            // Make sure the binary is a multiple of the page size by padding 0xFFs
-            // TODO(Micu Ana): check if the page-size differs
-            let page_size = 512;
-            let needs_padding = binary.len() % page_size != 0;
+    Ok(())
+}
 
-            if needs_padding {
-                let remaining = page_size - (binary.len() % page_size);
-                dbg!(remaining);
-                for _i in 0..remaining {
-                    binary.push(0xFF);
-                }
-            }
+/// Parse the header at the start of a TBF binary.
+pub(crate) fn parse_app_header(binary: &[u8]) -> Result<TbfHeader, TockloaderError> {
+    let lengths: &[u8; 8] = binary
+        .get(0..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(TockloaderError::InvalidTbf)?;
+    let (version, header_len, _) =
+        parse_tbf_header_lengths(lengths).map_err(|_| TockloaderError::InvalidTbf)?;
+    let header = binary
+        .get(0..header_len as usize)
+        .ok_or(TockloaderError::InvalidTbf)?;
+
+    parse_tbf_header(header, version).map_err(TockloaderError::ParsingError)
+}
 
-            // Get indices of pages that have valid data to write
-            let mut valid_pages: Vec<u8> = Vec::new();
-            for i in 0..(size as usize / page_size) {
-                for b in binary[(i * page_size)..((i + 1) * page_size)]
-                    .iter()
-                    .copied()
-                {
-                    if b != 0 {
-                        valid_pages.push(i.try_into().unwrap());
-                        break;
-                    }
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tbf_header::TbfBuilder;
 
-            // If there are no pages valid, all pages would have been removed, so we write them all
-            if valid_pages.is_empty() {
-                for i in 0..(size as usize / page_size) {
-                    valid_pages.push(i.try_into().unwrap());
-                }
-            }
+    const KERNEL_ATTRIBUTES: (u16, u16) = (2, 1);
 
-            // Include a blank page (if exists) after the end of a valid page. There might be a usable 0 on the next page
-            let mut ending_pages: Vec<u8> = Vec::new();
-            for &i in &valid_pages {
-                let mut iter = valid_pages.iter();
-                if !iter.any(|&x| x == (i + 1)) && (i + 1) < (size as usize / page_size) as u8 {
-                    ending_pages.push(i + 1);
-                }
-            }
+    fn board(kernel_attributes: bool) -> SystemAttributes {
+        let mut system_attributes = SystemAttributes::new(0x40000);
+        if kernel_attributes {
+            system_attributes.sentinel = Some("TOCK".to_owned());
+        }
+        system_attributes
+    }
 
-            for i in ending_pages {
-                valid_pages.push(i);
-            }
+    fn app(kernel_version: Option<(u16, u16)>) -> Vec<u8> {
+        let mut tbf = TbfBuilder::new(0x400).with_name("blink");
+        if let Some((major, minor)) = kernel_version {
+            tbf = tbf.with_kernel_version(major, minor);
+        }
+        tbf.build()
+    }
 
-            for i in valid_pages {
-                println!("Writing page number {}", i);
-                // Create the packet that we send to the bootloader
-                // First four bytes are the address of the page
-                let mut pkt = Vec::new();
-
-                // Then the bytes that go into the page
-                for b in binary[(i as usize * page_size)..((i + 1) as usize * page_size)]
-                    .iter()
-                    .copied()
-                {
-                    pkt.push(b);
-                }
-                let mut loader = session.target().flash_loader();
-
-                loader
-                    .add_data(
-                        (new_address as u32 + (i as usize * page_size) as u32).into(),
-                        &pkt,
-                    )
-                    .map_err(TockloaderError::ProbeRsWriteError)?;
-
-                let mut options = DownloadOptions::default();
-                options.keep_unwritten_bytes = true;
-
-                // Finally, the data can be programmed
-                loader
-                    .commit(&mut session, options)
-                    .map_err(TockloaderError::ProbeRsWriteError)?;
+    /// Whether the app is accepted without force. It always is with force.
+    fn accepted(
+        kernel_attributes: bool,
+        tlv: Option<(u16, u16)>,
+        tab_minimum: Option<(u16, u16)>,
+    ) -> bool {
+        let system_attributes = board(kernel_attributes);
+        let binary = app(tlv);
+        let check = |force| check_kernel_version(&system_attributes, &binary, tab_minimum, force);
+
+        assert!(check(true).is_ok());
+        match check(false) {
+            Ok(()) => true,
+            Err(TockloaderError::IncompatibleKernel {
+                required,
+                kernel_minimum,
+            }) => {
+                assert_eq!(Some(required), tlv.or(tab_minimum));
+                assert_eq!(kernel_minimum, KERNEL_ATTRIBUTES);
+                false
             }
+            Err(e) => panic!("Unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn accepts_apps_that_can_not_be_checked() {
+        for kernel_attributes in [false, true] {
+            assert!(accepted(kernel_attributes, None, None));
+        }
+        for version in [(1, 0), (2, 0), (3, 0)] {
+            assert!(accepted(false, Some(version), None));
+            assert!(accepted(false, None, Some(version)));
+            assert!(accepted(false, Some(version), Some(version)));
+        }
+    }
 
-            Ok(())
+    #[test]
+    fn accepts_apps_for_the_same_or_a_newer_major_version() {
+        for version in [(2, 0), (2, 1), (2, 2), (3, 0)] {
+            assert!(accepted(true, Some(version), None));
+            assert!(accepted(true, None, Some(version)));
         }
     }
+
+    #[test]
+    fn rejects_apps_for_an_older_major_version_without_force() {
+        assert!(!accepted(true, Some((1, 0)), None));
+        assert!(!accepted(true, None, Some((1, 0))));
+        assert!(!accepted(true, Some((1, 0)), Some((1, 0))));
+    }
+
+    #[test]
+    fn prefers_the_kernel_version_tlv_to_the_tab_minimum() {
+        assert!(accepted(true, Some((2, 0)), Some((1, 0))));
+        assert!(!accepted(true, Some((1, 0)), Some((2, 0))));
+    }
 }
@@ -0,0 +1,305 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! A Tock bootloader simulated in memory, to test the serial path without a board.
+//!
+//! The simulator speaks the bootloader protocol over any `AsyncRead + AsyncWrite` stream, usually
+//! one end of a [`tokio::io::duplex`] pipe, and keeps the flash in a vector starting at address 0.
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Encoder, Framed};
+
+use crate::attributes::bootloader_attributes::{
+    encode_attribute, Attributes, ATTRIBUTES_ADDRESS, ATTRIBUTE_COUNT, ATTRIBUTE_LEN,
+};
+use crate::bootloader_serial::codec::{BootloaderCodec, ResponseFrame, INFO_LEN};
+use crate::bootloader_serial::{Command, Response, BAUD_RATE_CONFIRM, BAUD_RATE_REQUEST};
+use crate::connection::serial::{SwitchBaudRate, DEFAULT_BAUD_RATE};
+use crate::errors::TockloaderError;
+
+pub use crate::tbf_header::TbfBuilder;
+
+// Size of the buffer of the pipe created by `spawn`.
+const DUPLEX_BUFFER_LEN: usize = 4096;
+
+pub struct SimulatedBootloader {
+    flash: Vec<u8>,
+    page_size: usize,
+    info: String,
+    dropped_responses: usize,
+    garbled_responses: usize,
+    // `None` if the bootloader does not know the ChangeBaudRate command
+    baud_rates: Option<Vec<u32>>,
+    baud_rate: u32,
+    requested_baud_rate: Option<u32>,
+}
+
+impl SimulatedBootloader {
+    /// A bootloader with `flash_size` bytes of erased flash, written by pages of `page_size`
+    /// bytes.
+    pub fn new(flash_size: usize, page_size: usize) -> SimulatedBootloader {
+        SimulatedBootloader {
+            flash: vec![0xFF; flash_size],
+            page_size,
+            info: String::new(),
+            dropped_responses: 0,
+            garbled_responses: 0,
+            baud_rates: None,
+            baud_rate: DEFAULT_BAUD_RATE,
+            requested_baud_rate: None,
+        }
+    }
+
+    /// Store `key` and `value` in the bootloader attributes, as the board would.
+    pub fn with_attribute(
+        mut self,
+        key: &str,
+        value: &str,
+    ) -> Result<SimulatedBootloader, TockloaderError> {
+        let start = ATTRIBUTES_ADDRESS as usize;
+        let table = &self.flash[start..start + ATTRIBUTE_COUNT * ATTRIBUTE_LEN];
+        let index = Attributes::parse(table)
+            .slot_for(key)
+            .ok_or_else(|| TockloaderError::AttributeTableFull(key.to_owned()))?;
+
+        let slot_address = start + index * ATTRIBUTE_LEN;
+        self.flash[slot_address..slot_address + ATTRIBUTE_LEN]
+            .copy_from_slice(&encode_attribute(key, value)?);
+        Ok(self)
+    }
+
+    /// Copy `data` to the flash at `address`, for example a bootloader version or apps.
+    pub fn with_flash(mut self, address: usize, data: &[u8]) -> SimulatedBootloader {
+        self.flash[address..address + data.len()].copy_from_slice(data);
+        self
+    }
+
+    /// Set the string returned by the info command.
+    pub fn with_info(mut self, info: &str) -> SimulatedBootloader {
+        self.info = info.to_owned();
+        self
+    }
+
+    /// Do not send the next `count` responses, as if they were lost on the line.
+    pub fn with_dropped_responses(mut self, count: usize) -> SimulatedBootloader {
+        self.dropped_responses = count;
+        self
+    }
+
+    /// Corrupt the first byte of the next `count` responses.
+    pub fn with_garbled_responses(mut self, count: usize) -> SimulatedBootloader {
+        self.garbled_responses = count;
+        self
+    }
+
+    /// Accept switching to `baud_rates`. Without them, the bootloader behaves like older ones
+    /// that do not know the command to change the baud rate.
+    pub fn with_baud_rates(mut self, baud_rates: &[u32]) -> SimulatedBootloader {
+        self.baud_rates = Some(baud_rates.to_vec());
+        self
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Baud rate the bootloader switched to, as there is no real line to change.
+    pub fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    /// Run the bootloader in a new task and return the stream to talk to it. The task gives the
+    /// bootloader back once the stream is dropped or the exit command is received.
+    pub fn spawn(self) -> (DuplexStream, JoinHandle<io::Result<SimulatedBootloader>>) {
+        let (client, bootloader) = io::duplex(DUPLEX_BUFFER_LEN);
+        (client, tokio::spawn(self.run(bootloader)))
+    }
+
+    /// Answer the commands received on `stream` until it is closed or the exit command is
+    /// received.
+    pub async fn run(
+        mut self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+    ) -> io::Result<SimulatedBootloader> {
+        let mut framed = Framed::new(stream, BootloaderCodec::default());
+
+        while let Some(frame) = framed.next().await {
+            let frame = frame?;
+            let command = frame.command();
+            if matches!(command, Ok(Command::Exit)) {
+                break;
+            }
+
+            let Some(response) = self.handle(command, &frame.args) else {
+                continue;
+            };
+            if self.dropped_responses > 0 {
+                self.dropped_responses -= 1;
+            } else if self.garbled_responses > 0 {
+                self.garbled_responses -= 1;
+                let mut packet = BytesMut::new();
+                BootloaderCodec::default().encode(response, &mut packet)?;
+                packet[0] = 0x00;
+                framed.get_mut().write_all(&packet).await?;
+            } else {
+                framed.send(response).await?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Execute a command and build the response to send back, if any.
+    fn handle(&mut self, command: Result<Command, u8>, args: &[u8]) -> Option<ResponseFrame> {
+        let result = match command {
+            // The buffer is reset for every command anyway
+            Ok(Command::Reset) => return None,
+            Ok(Command::Ping) => Ok((Response::Pong, Vec::new())),
+            Ok(Command::Info) => Ok(self.info()),
+            Ok(Command::ReadRange) => self.read_range(args),
+            Ok(Command::ErasePage) => self.erase_page(args),
+            Ok(Command::WritePage) => self.write_page(args),
+            Ok(Command::CRCInternalFlash) => self.crc(args),
+            Ok(Command::GetAttribute) => self.get_attribute(args),
+            Ok(Command::SetAttribute) => self.set_attribute(args),
+            Ok(Command::ChangeBaudRate) if self.baud_rates.is_some() => self.change_baud_rate(args),
+            _ => Err(Response::Unknown),
+        };
+
+        let (response, data) = result.unwrap_or_else(|error| (error, Vec::new()));
+        Some(ResponseFrame::new(response, data))
+    }
+
+    fn info(&self) -> (Response, Vec<u8>) {
+        let info = &self.info.as_bytes()[..self.info.len().min(INFO_LEN - 1)];
+        let mut data = vec![info.len() as u8];
+        data.extend_from_slice(info);
+        data.resize(INFO_LEN, 0);
+        (Response::Info, data)
+    }
+
+    fn read_range(&self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        let [a0, a1, a2, a3, l0, l1] = *args else {
+            return Err(Response::BadArgs);
+        };
+        let address = u32::from_le_bytes([a0, a1, a2, a3]) as usize;
+        let length = u16::from_le_bytes([l0, l1]) as usize;
+
+        let range = self.range(address, length)?;
+        Ok((Response::ReadRange, self.flash[range].to_vec()))
+    }
+
+    fn erase_page(&mut self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        let [a0, a1, a2, a3] = *args else {
+            return Err(Response::BadArgs);
+        };
+        let address = u32::from_le_bytes([a0, a1, a2, a3]) as usize;
+
+        let range = self.page(address)?;
+        self.flash[range].fill(0xFF);
+        Ok((Response::OK, Vec::new()))
+    }
+
+    fn write_page(&mut self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        if args.len() != 4 + self.page_size {
+            return Err(Response::BadArgs);
+        }
+        let address = u32::from_le_bytes([args[0], args[1], args[2], args[3]]) as usize;
+
+        let range = self.page(address)?;
+        self.flash[range].copy_from_slice(&args[4..]);
+        Ok((Response::OK, Vec::new()))
+    }
+
+    fn crc(&self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        let [a0, a1, a2, a3, l0, l1, l2, l3] = *args else {
+            return Err(Response::BadArgs);
+        };
+        let address = u32::from_le_bytes([a0, a1, a2, a3]) as usize;
+        let length = u32::from_le_bytes([l0, l1, l2, l3]) as usize;
+
+        let range = self.range(address, length)?;
+        let crc = crc32fast::hash(&self.flash[range]);
+        Ok((Response::CRCInternalFlash, crc.to_le_bytes().to_vec()))
+    }
+
+    fn get_attribute(&self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        let [index] = *args else {
+            return Err(Response::BadArgs);
+        };
+
+        let range = Self::attribute(index)?;
+        Ok((Response::GetAttribute, self.flash[range].to_vec()))
+    }
+
+    fn set_attribute(&mut self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        let Some((&index, slot)) = args.split_first() else {
+            return Err(Response::BadArgs);
+        };
+        if slot.len() != ATTRIBUTE_LEN {
+            return Err(Response::BadArgs);
+        }
+
+        let range = Self::attribute(index)?;
+        self.flash[range].copy_from_slice(slot);
+        Ok((Response::OK, Vec::new()))
+    }
+
+    fn change_baud_rate(&mut self, args: &[u8]) -> Result<(Response, Vec<u8>), Response> {
+        let [step, b0, b1, b2, b3] = *args else {
+            return Err(Response::BadArgs);
+        };
+        let baud_rate = u32::from_le_bytes([b0, b1, b2, b3]);
+
+        match step {
+            BAUD_RATE_REQUEST => {
+                if !self
+                    .baud_rates
+                    .as_ref()
+                    .is_some_and(|rates| rates.contains(&baud_rate))
+                {
+                    return Err(Response::ChangeBaudFail);
+                }
+                self.requested_baud_rate = Some(baud_rate);
+            }
+            BAUD_RATE_CONFIRM if self.requested_baud_rate == Some(baud_rate) => {
+                self.baud_rate = baud_rate;
+            }
+            _ => return Err(Response::BadArgs),
+        }
+        Ok((Response::OK, Vec::new()))
+    }
+
+    fn range(&self, address: usize, length: usize) -> Result<std::ops::Range<usize>, Response> {
+        match address.checked_add(length) {
+            Some(end) if end <= self.flash.len() => Ok(address..end),
+            _ => Err(Response::BadAddr),
+        }
+    }
+
+    fn page(&self, address: usize) -> Result<std::ops::Range<usize>, Response> {
+        if !address.is_multiple_of(self.page_size) {
+            return Err(Response::BadAddr);
+        }
+        self.range(address, self.page_size)
+    }
+
+    fn attribute(index: u8) -> Result<std::ops::Range<usize>, Response> {
+        let index = index as usize;
+        if index >= ATTRIBUTE_COUNT {
+            return Err(Response::BadArgs);
+        }
+        let address = ATTRIBUTES_ADDRESS as usize + index * ATTRIBUTE_LEN;
+        Ok(address..address + ATTRIBUTE_LEN)
+    }
+}
+
+/// The simulated bootloader is reached through a pipe, which has no baud rate to change.
+impl SwitchBaudRate for DuplexStream {
+    fn switch_baud_rate(&mut self, _baud_rate: u32) -> Result<(), TockloaderError> {
+        Ok(())
+    }
+}
@@ -37,7 +37,6 @@ where
     Ok(opt.map(|s| s.split(',').map(|s| s.trim().to_string()).collect()))
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub(super) struct TockKernelVersion {
     pub major: u32,
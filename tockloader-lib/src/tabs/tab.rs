@@ -3,15 +3,67 @@
 // Copyright OXIDOS AUTOMOTIVE 2024.
 
 use crate::errors::TockloaderError;
+use crate::parse_app_header;
 use crate::tabs::metadata::Metadata;
+use crate::tbf_header::fixed_tbf_address;
 use std::{fs::File, io::Read};
 use tar::Archive;
+use tbf_parser::types::TbfHeader;
 
 struct TbfFile {
     pub filename: String,
     pub data: Vec<u8>,
 }
 
+/// A TBF of a tab built for a given architecture.
+pub struct AppBinary {
+    pub filename: String,
+    pub data: Vec<u8>,
+    pub header: TbfHeader,
+}
+
+impl AppBinary {
+    fn new(filename: String, data: Vec<u8>) -> Result<AppBinary, TockloaderError> {
+        let header = parse_app_header(&data)?;
+
+        Ok(AppBinary {
+            filename,
+            data,
+            header,
+        })
+    }
+
+    /// Flash address the code of the app was compiled for, `None` if it is position independent.
+    pub fn fixed_address_flash(&self) -> Option<u32> {
+        self.header.get_fixed_address_flash()
+    }
+
+    /// RAM address the app was compiled for, `None` if it is position independent.
+    pub fn fixed_address_ram(&self) -> Option<u32> {
+        self.header.get_fixed_address_ram()
+    }
+
+    /// Address the TBF has to be written at for its code to end up at its fixed flash address.
+    pub fn fixed_tbf_address(&self) -> Option<u64> {
+        fixed_tbf_address(&self.header)
+    }
+}
+
+/// Architecture a TBF in a tab is built for. Files are named `<arch>.tbf`, or
+/// `<arch>.<address>[.<address>].tbf` for builds with fixed flash (and RAM) addresses. Anything else
+/// is not a TBF of the tab.
+fn tbf_arch(filename: &str) -> Option<&str> {
+    let mut parts = filename.strip_suffix(".tbf")?.split('.');
+    let arch = parts.next()?;
+    let addresses_valid = parts.all(|address| {
+        address
+            .strip_prefix("0x")
+            .is_some_and(|hex| u64::from_str_radix(hex, 16).is_ok())
+    });
+
+    addresses_valid.then_some(arch)
+}
+
 pub struct Tab {
     metadata: Metadata,
     tbf_files: Vec<TbfFile>,
@@ -56,11 +108,15 @@ impl Tab {
         }
     }
 
-    pub fn is_compatible_with_kernel_verison(&self, _kernel_version: u32) -> bool {
-        // Kernel version seems to not be working properly on the microbit bootloader. It is always
-        // "1" despite the actual version.
-        // return self.metadata.minimum_tock_kernel_version.major <= kernel_version;
-        true
+    /// Oldest kernel version, as `(major, minor)`, the apps of the tab can run on.
+    pub fn minimum_kernel_version(&self) -> (u16, u16) {
+        let version = &self.metadata.minimum_tock_kernel_version;
+        (version.major as u16, version.minor as u16)
+    }
+
+    /// Boards the tab is restricted to, `None` meaning that it can be installed on any board.
+    pub fn compatible_boards(&self) -> Option<&[String]> {
+        self.metadata.only_for_boards.as_deref()
     }
 
     pub fn is_compatible_with_board(&self, board: &String) -> bool {
@@ -71,13 +127,94 @@ impl Tab {
         }
     }
 
-    pub fn extract_binary(&self, arch: &str) -> Result<Vec<u8>, TockloaderError> {
-        for file in &self.tbf_files {
-            if file.filename.starts_with(arch) {
-                return Ok(file.data.clone());
-            }
+    /// Every TBF of the tab built for `arch`.
+    pub fn extract_binaries(&self, arch: &str) -> Result<Vec<AppBinary>, TockloaderError> {
+        let binaries = self
+            .tbf_files
+            .iter()
+            .filter(|file| tbf_arch(&file.filename) == Some(arch))
+            .map(|file| AppBinary::new(file.filename.clone(), file.data.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if binaries.is_empty() {
+            return Err(TockloaderError::NoBinaryError(arch.to_owned()));
+        }
+        Ok(binaries)
+    }
+}
+
+/// Pick the binary to install when the free flash starts at `free_address`, or at
+/// `replaced_address` as well if the binary takes the place of an installed app. A position
+/// independent binary can go anywhere, otherwise the binary with the lowest fixed address that is
+/// still free is used, leaving as much space as possible for later apps.
+pub fn select_binary(
+    binaries: Vec<AppBinary>,
+    free_address: u64,
+    replaced_address: Option<u64>,
+) -> Option<AppBinary> {
+    let (fixed, position_independent): (Vec<_>, Vec<_>) = binaries
+        .into_iter()
+        .partition(|binary| binary.fixed_address_flash().is_some());
+
+    position_independent.into_iter().next().or_else(|| {
+        fixed
+            .into_iter()
+            .filter(|binary| {
+                binary.fixed_tbf_address() >= Some(free_address)
+                    || (replaced_address.is_some()
+                        && binary.fixed_tbf_address() == replaced_address)
+            })
+            .min_by_key(|binary| binary.fixed_tbf_address())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tbf_header::TbfBuilder;
+
+    /// A binary whose TBF has to be written at `tbf_address`, or a position independent one.
+    fn binary(tbf_address: Option<u32>) -> AppBinary {
+        let mut tbf = TbfBuilder::new(0x1000);
+        if let Some(tbf_address) = tbf_address {
+            tbf = tbf.with_fixed_address(tbf_address);
         }
+        let tbf = tbf.build();
+        AppBinary::new(format!("{tbf_address:?}.tbf"), tbf).unwrap()
+    }
+
+    fn selected(
+        tbf_addresses: &[Option<u32>],
+        free_address: u64,
+        replaced_address: Option<u64>,
+    ) -> Option<Option<u64>> {
+        let binaries = tbf_addresses
+            .iter()
+            .map(|&address| binary(address))
+            .collect();
+        select_binary(binaries, free_address, replaced_address)
+            .map(|binary| binary.fixed_tbf_address())
+    }
+
+    #[test]
+    fn prefers_position_independent_binaries() {
+        assert_eq!(selected(&[Some(0x48000), None], 0x44000, None), Some(None));
+    }
+
+    #[test]
+    fn picks_lowest_free_fixed_address() {
+        let addresses = [Some(0x50000), Some(0x40000), Some(0x48000)];
+        assert_eq!(selected(&addresses, 0x44000, None), Some(Some(0x48000)));
+        assert_eq!(selected(&addresses, 0x50001, None), None);
+    }
 
-        Err(TockloaderError::NoBinaryError(arch.to_owned()))
+    #[test]
+    fn accepts_address_of_replaced_app() {
+        let addresses = [Some(0x48000), Some(0x40000)];
+        assert_eq!(
+            selected(&addresses, 0x44000, Some(0x40000)),
+            Some(Some(0x40000))
+        );
+        assert_eq!(selected(&[Some(0x40000)], 0x44000, Some(0x3C000)), None);
     }
 }
@@ -0,0 +1,244 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! Helpers to build raw TBF v2 headers, or patch them before writing them back to flash.
+
+use tbf_parser::types::TbfHeader;
+
+/// Bit 0 of the flags is the enable/disable bit.
+pub(crate) const FLAG_ENABLED: u32 = 0x00000001;
+/// Bit 1 of the flags is the sticky bit.
+pub(crate) const FLAG_STICKY: u32 = 0x00000002;
+
+pub(crate) const FLAGS_OFFSET: usize = 8;
+pub(crate) const CHECKSUM_OFFSET: usize = 12;
+
+/// Length of the base header: version, header size, total size, flags and checksum.
+pub(crate) const BASE_HEADER_LEN: usize = 16;
+const TBF_VERSION: u16 = 2;
+
+fn read_word(header: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(
+        header[offset..offset + 4]
+            .try_into()
+            .expect("Slice is exactly 4 bytes long."),
+    )
+}
+
+/// The checksum is the XOR of each 4 byte word in the header, skipping the checksum field.
+pub(crate) fn header_checksum(header: &[u8]) -> u32 {
+    header
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(i, _)| *i != CHECKSUM_OFFSET / 4)
+        .fold(0, |checksum, (_, chunk)| {
+            checksum ^ u32::from_le_bytes(chunk.try_into().expect("Chunk is 4 bytes long."))
+        })
+}
+
+/// Set or clear `flag` in the header and recompute the checksum. Returns whether the header
+/// changed.
+pub(crate) fn set_header_flag(header: &mut [u8], flag: u32, value: bool) -> bool {
+    let flags = read_word(header, FLAGS_OFFSET);
+    let new_flags = if value { flags | flag } else { flags & !flag };
+    if new_flags == flags {
+        return false;
+    }
+
+    header[FLAGS_OFFSET..FLAGS_OFFSET + 4].copy_from_slice(&new_flags.to_le_bytes());
+    let checksum = header_checksum(header);
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+    true
+}
+
+/// Build a padding TBF of `size` bytes: a base header without any TLV, followed by erased flash.
+/// The kernel skips it while walking the list of apps. `size` must be at least
+/// [`BASE_HEADER_LEN`].
+pub(crate) fn padding_tbf(size: u32) -> Vec<u8> {
+    let mut tbf = vec![0xFF; size as usize];
+    tbf[0..2].copy_from_slice(&TBF_VERSION.to_le_bytes());
+    tbf[2..4].copy_from_slice(&(BASE_HEADER_LEN as u16).to_le_bytes());
+    tbf[4..8].copy_from_slice(&size.to_le_bytes());
+    tbf[FLAGS_OFFSET..FLAGS_OFFSET + 4].copy_from_slice(&0u32.to_le_bytes());
+
+    let checksum = header_checksum(&tbf[0..BASE_HEADER_LEN]);
+    tbf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+    tbf
+}
+
+/// Address a TBF has to be written at for its code to end up at the fixed flash address it was
+/// compiled for, `None` if it is position independent. The header and the protected region come
+/// right before the code.
+pub(crate) fn fixed_tbf_address(header: &TbfHeader) -> Option<u64> {
+    header
+        .get_fixed_address_flash()
+        .and_then(|address| (address as u64).checked_sub(header.get_protected_size() as u64))
+}
+
+/// Builder of app TBFs for tests: a header with a main TLV and the optional TLVs set on the
+/// builder, followed by zeros up to the total size.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone, Debug)]
+pub struct TbfBuilder {
+    total_size: u32,
+    flags: u32,
+    package_name: Option<String>,
+    fixed_tbf_address: Option<u32>,
+    kernel_version: Option<(u16, u16)>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl TbfBuilder {
+    /// An enabled app of `total_size` bytes.
+    pub fn new(total_size: u32) -> TbfBuilder {
+        TbfBuilder {
+            total_size,
+            flags: FLAG_ENABLED,
+            package_name: None,
+            fixed_tbf_address: None,
+            kernel_version: None,
+        }
+    }
+
+    /// Add a package name TLV.
+    pub fn with_name(mut self, name: &str) -> TbfBuilder {
+        self.package_name = Some(name.to_owned());
+        self
+    }
+
+    /// Add a fixed addresses TLV, so that the TBF has to be written at `tbf_address`.
+    pub fn with_fixed_address(mut self, tbf_address: u32) -> TbfBuilder {
+        self.fixed_tbf_address = Some(tbf_address);
+        self
+    }
+
+    /// Add a kernel version TLV, the app requiring Tock `major.minor`.
+    pub fn with_kernel_version(mut self, major: u16, minor: u16) -> TbfBuilder {
+        self.kernel_version = Some((major, minor));
+        self
+    }
+
+    /// Replace the flags of the header, [`FLAG_ENABLED`] by default.
+    pub fn with_flags(mut self, flags: u32) -> TbfBuilder {
+        self.flags = flags;
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut tlvs = Vec::new();
+        // Main: init function offset, protected trailer size, minimum RAM size
+        tlvs.extend(1u16.to_le_bytes());
+        tlvs.extend(12u16.to_le_bytes());
+        tlvs.extend([0u32, 0, 4096].iter().flat_map(|word| word.to_le_bytes()));
+        if let Some(name) = &self.package_name {
+            tlvs.extend(3u16.to_le_bytes());
+            tlvs.extend((name.len() as u16).to_le_bytes());
+            tlvs.extend(name.as_bytes());
+            tlvs.resize(tlvs.len().next_multiple_of(4), 0);
+        }
+        if let Some((major, minor)) = self.kernel_version {
+            tlvs.extend(8u16.to_le_bytes());
+            tlvs.extend(4u16.to_le_bytes());
+            tlvs.extend(major.to_le_bytes());
+            tlvs.extend(minor.to_le_bytes());
+        }
+        // The fixed addresses TLV comes last, its flash address depends on the header length
+        let header_len =
+            (BASE_HEADER_LEN + tlvs.len()) as u32 + self.fixed_tbf_address.map_or(0, |_| 12);
+        if let Some(tbf_address) = self.fixed_tbf_address {
+            // Fixed addresses: RAM and flash, the code starting right after the header
+            tlvs.extend(5u16.to_le_bytes());
+            tlvs.extend(8u16.to_le_bytes());
+            tlvs.extend(u32::MAX.to_le_bytes());
+            tlvs.extend((tbf_address + header_len).to_le_bytes());
+        }
+
+        let mut tbf = Vec::new();
+        tbf.extend(TBF_VERSION.to_le_bytes());
+        tbf.extend((header_len as u16).to_le_bytes());
+        tbf.extend(self.total_size.to_le_bytes());
+        tbf.extend(self.flags.to_le_bytes());
+        tbf.extend(0u32.to_le_bytes());
+        tbf.extend(tlvs);
+
+        let checksum = header_checksum(&tbf);
+        tbf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        tbf.resize(self.total_size as usize, 0);
+        tbf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tbf_parser::parse::{parse_tbf_header, parse_tbf_header_lengths};
+
+    #[test]
+    fn padding_tbf_is_a_padding_header() {
+        for size in [BASE_HEADER_LEN as u32, 100, 0x4000] {
+            let tbf = padding_tbf(size);
+            assert_eq!(tbf.len(), size as usize);
+
+            let Ok((version, header_len, total_size)) =
+                parse_tbf_header_lengths(&tbf[0..8].try_into().unwrap())
+            else {
+                panic!("Padding TBF of {size} bytes has invalid lengths.");
+            };
+            assert_eq!(total_size, size);
+            let header = parse_tbf_header(&tbf[0..header_len as usize], version).unwrap();
+            assert!(matches!(header, TbfHeader::Padding(_)));
+            assert!(!header.is_app());
+        }
+    }
+
+    #[test]
+    fn builds_app_tbfs() {
+        let tbf = TbfBuilder::new(0x1000)
+            .with_name("blink")
+            .with_fixed_address(0x40000)
+            .with_flags(FLAG_ENABLED | FLAG_STICKY)
+            .build();
+        assert_eq!(tbf.len(), 0x1000);
+
+        let header = parse_tbf_header(&tbf[0..56], 2).unwrap();
+        assert_eq!(header.length(), 56);
+        assert_eq!(header.get_package_name(), Some("blink"));
+        assert_eq!(fixed_tbf_address(&header), Some(0x40000));
+        assert!(header.enabled());
+        assert!(header.sticky());
+
+        let tbf = TbfBuilder::new(0x400).build();
+        let header = parse_tbf_header(&tbf[0..32], 2).unwrap();
+        assert_eq!(header.get_package_name(), None);
+        assert_eq!(fixed_tbf_address(&header), None);
+    }
+
+    #[test]
+    fn checksum_skips_the_checksum_field() {
+        let mut header = [0u8; 20];
+        header[0..4].copy_from_slice(&0x0001_0002u32.to_le_bytes());
+        header[8..12].copy_from_slice(&0x0100_0000u32.to_le_bytes());
+        header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        header[16..20].copy_from_slice(&0x0000_0F00u32.to_le_bytes());
+
+        assert_eq!(header_checksum(&header), 0x0100_0F00 ^ 0x0001_0002);
+    }
+
+    #[test]
+    fn set_header_flag_updates_the_checksum() {
+        let mut tbf = padding_tbf(64);
+        let header = &mut tbf[0..BASE_HEADER_LEN];
+
+        assert!(set_header_flag(header, FLAG_STICKY, true));
+        assert_eq!(read_word(header, FLAGS_OFFSET), FLAG_STICKY);
+        assert_eq!(read_word(header, CHECKSUM_OFFSET), header_checksum(header));
+
+        assert!(!set_header_flag(header, FLAG_STICKY, true));
+        assert!(set_header_flag(header, FLAG_ENABLED, true));
+        assert!(set_header_flag(header, FLAG_STICKY, false));
+        assert_eq!(read_word(header, FLAGS_OFFSET), FLAG_ENABLED);
+        assert_eq!(read_word(header, CHECKSUM_OFFSET), header_checksum(header));
+        parse_tbf_header(header, 2).unwrap();
+    }
+}
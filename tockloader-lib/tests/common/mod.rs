@@ -0,0 +1,55 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+//! Helpers shared by the integration tests.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tockloader_lib::connection::TockloaderConnection;
+use tockloader_lib::events::Observer;
+use tockloader_lib::simulator::TbfBuilder;
+use tockloader_lib::{install_tbf, list, InstallOptions};
+
+/// A TBF with a main and a package name TLV, padded to `total_size`.
+pub fn tbf(name: &str, total_size: u32) -> Vec<u8> {
+    TbfBuilder::new(total_size).with_name(name).build()
+}
+
+/// Path of a new file in a temporary directory private to this test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!("tockloader-tests-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(format!(
+        "{}-{name}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Install an app called `name`, `size` bytes long, with the default options.
+pub async fn install_app(conn: &mut impl TockloaderConnection, name: &str, size: u32) {
+    let path = temp_path(&format!("{name}.tbf"));
+    std::fs::write(&path, tbf(name, size)).unwrap();
+    install_tbf(
+        conn,
+        &path,
+        &InstallOptions::default(),
+        &Observer::default(),
+    )
+    .await
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Names of the apps installed, in flash order.
+pub async fn app_names(conn: &mut impl TockloaderConnection) -> Vec<String> {
+    list(conn, &Observer::default())
+        .await
+        .unwrap()
+        .iter()
+        .map(|app| app.tbf_header.get_package_name().unwrap().to_owned())
+        .collect()
+}
@@ -0,0 +1,144 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+mod common;
+
+use common::{app_names, install_app, tbf, temp_path};
+
+use tockloader_lib::connection::flash_file::FlashFileConnection;
+use tockloader_lib::connection::TockloaderConnection;
+use tockloader_lib::errors::TockloaderError;
+use tockloader_lib::events::Observer;
+use tockloader_lib::{list, set_app_enabled, set_app_sticky, uninstall_app, write_attribute};
+
+const FLASH_SIZE: u64 = 0x20000;
+const APP_ADDRESS: u64 = 0x10000;
+
+/// An image of a board with a bootloader, its attributes and no apps.
+async fn board() -> FlashFileConnection {
+    let mut conn = FlashFileConnection::open(temp_path("flash.bin"), 0, Some(FLASH_SIZE)).unwrap();
+    conn.write_range(0x40E, b"1.1.0\0\0\0").await.unwrap();
+    for (key, value) in [
+        ("board", "sim"),
+        ("arch", "cortex-m4"),
+        ("appaddr", "0x10000"),
+    ] {
+        write_attribute(&mut conn, key, value).await.unwrap();
+    }
+    conn
+}
+
+#[tokio::test]
+async fn creates_images_only_with_a_size() {
+    let path = temp_path("missing.bin");
+    assert!(matches!(
+        FlashFileConnection::open(&path, 0, None),
+        Err(TockloaderError::UnusableFlashFile(_))
+    ));
+    assert!(!path.exists());
+
+    let mut conn = FlashFileConnection::open(&path, 0x8000, Some(0x1000)).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), vec![0xFF; 0x1000]);
+    assert_eq!(conn.read_range(0x8FFC, 4).await.unwrap(), [0xFF; 4]);
+    assert!(matches!(
+        conn.read_range(0x8FFC, 8).await,
+        Err(TockloaderError::AddressOutOfRange {
+            address: 0x8FFC,
+            length: 8
+        })
+    ));
+    assert!(matches!(
+        conn.write_range(0x7000, &[0; 4]).await,
+        Err(TockloaderError::AddressOutOfRange { .. })
+    ));
+
+    // An existing image keeps its size
+    drop(conn);
+    let conn = FlashFileConnection::open(&path, 0x8000, None).unwrap();
+    drop(conn);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0x1000);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn installs_and_uninstalls_apps() {
+    let mut conn = board().await;
+
+    for (name, size) in [("blink", 1024), ("hello", 2048), ("sensors", 512)] {
+        install_app(&mut conn, name, size).await;
+    }
+    assert_eq!(app_names(&mut conn).await, ["blink", "hello", "sensors"]);
+    assert_eq!(
+        conn.read_range(APP_ADDRESS, 1024).await.unwrap(),
+        tbf("blink", 1024)
+    );
+
+    uninstall_app(&mut conn, "hello", false).await.unwrap();
+    assert_eq!(app_names(&mut conn).await, ["blink", "sensors"]);
+    assert!(matches!(
+        uninstall_app(&mut conn, "hello", false).await,
+        Err(TockloaderError::AppNotFound(_))
+    ));
+
+    // Installing an app again replaces it
+    install_app(&mut conn, "blink", 1024).await;
+    assert_eq!(app_names(&mut conn).await, ["blink", "sensors"]);
+}
+
+#[tokio::test]
+async fn installs_apps_over_stale_flash() {
+    let mut conn = board().await;
+    // Leftovers of an older app, which must not show through the zeros of the new one
+    conn.write_range(APP_ADDRESS + 1024, &[0xA5; 1024])
+        .await
+        .unwrap();
+
+    install_app(&mut conn, "blink", 2048).await;
+    assert_eq!(
+        conn.read_range(APP_ADDRESS, 2048).await.unwrap(),
+        tbf("blink", 2048)
+    );
+}
+
+#[tokio::test]
+async fn enables_and_disables_apps() {
+    let mut conn = board().await;
+    install_app(&mut conn, "blink", 1024).await;
+    install_app(&mut conn, "hello", 1024).await;
+
+    set_app_enabled(&mut conn, "hello", false).await.unwrap();
+    let enabled: Vec<_> = list(&mut conn, &Observer::default())
+        .await
+        .unwrap()
+        .iter()
+        .map(|app| app.tbf_header.enabled())
+        .collect();
+    assert_eq!(enabled, [true, false]);
+
+    set_app_enabled(&mut conn, "hello", true).await.unwrap();
+    let hello = conn.read_range(APP_ADDRESS + 1024, 1024).await.unwrap();
+    assert_eq!(hello, tbf("hello", 1024));
+
+    assert!(matches!(
+        set_app_enabled(&mut conn, "sensors", true).await,
+        Err(TockloaderError::AppNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn uninstalls_sticky_apps_only_with_force() {
+    let mut conn = board().await;
+    install_app(&mut conn, "blink", 1024).await;
+    install_app(&mut conn, "hello", 1024).await;
+
+    set_app_sticky(&mut conn, "hello", true).await.unwrap();
+    assert!(matches!(
+        uninstall_app(&mut conn, "hello", false).await,
+        Err(TockloaderError::StickyApp(_))
+    ));
+    assert_eq!(app_names(&mut conn).await, ["blink", "hello"]);
+
+    uninstall_app(&mut conn, "hello", true).await.unwrap();
+    assert_eq!(app_names(&mut conn).await, ["blink"]);
+}
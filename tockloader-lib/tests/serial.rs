@@ -0,0 +1,261 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright OXIDOS AUTOMOTIVE 2024.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{app_names, install_app, tbf};
+
+use tockloader_lib::connection::serial::{SerialConnection, DEFAULT_BAUD_RATE};
+use tockloader_lib::connection::TockloaderConnection;
+use tockloader_lib::errors::{BootloaderError, TockloaderError};
+use tockloader_lib::events::Observer;
+use tockloader_lib::simulator::SimulatedBootloader;
+use tockloader_lib::{info, list, read_attributes, uninstall_app, write_attribute};
+use tokio::io::DuplexStream;
+use tokio::sync::Mutex;
+
+const FLASH_SIZE: usize = 0x20000;
+const PAGE_SIZE: usize = 512;
+const APP_ADDRESS: u64 = 0x10000;
+
+/// A board with a bootloader, its attributes and no apps.
+fn board() -> SimulatedBootloader {
+    SimulatedBootloader::new(FLASH_SIZE, PAGE_SIZE)
+        .with_flash(0x40E, b"1.1.0\0\0\0")
+        .with_attribute("board", "sim")
+        .unwrap()
+        .with_attribute("arch", "cortex-m4")
+        .unwrap()
+        .with_attribute("appaddr", "0x10000")
+        .unwrap()
+}
+
+/// A connection that skips the initial ping, so that every response goes to the test.
+fn connection(stream: DuplexStream) -> SerialConnection<DuplexStream> {
+    let mut conn = SerialConnection::new(Arc::new(Mutex::new(stream)));
+    conn.set_timeout(Duration::from_millis(100));
+    conn
+}
+
+#[tokio::test]
+async fn uses_page_size_attribute() {
+    let bootloader = SimulatedBootloader::new(FLASH_SIZE, 1024)
+        .with_attribute("pagesize", "1024")
+        .unwrap();
+    let (stream, _) = bootloader.spawn();
+
+    let conn = SerialConnection::connect(stream).await.unwrap();
+    assert_eq!(conn.page_size(), 1024);
+}
+
+#[tokio::test]
+async fn ignores_invalid_page_size_attribute() {
+    for page_size in ["0", "1000"] {
+        let bootloader = board().with_attribute("pagesize", page_size).unwrap();
+        let (stream, _) = bootloader.spawn();
+
+        let mut conn = SerialConnection::connect(stream).await.unwrap();
+        assert_eq!(conn.page_size(), PAGE_SIZE);
+        assert!(matches!(
+            conn.set_page_size(0),
+            Err(TockloaderError::InvalidPageSize(0))
+        ));
+        assert_eq!(conn.page_size(), PAGE_SIZE);
+    }
+}
+
+#[tokio::test]
+async fn writes_reads_and_erases_pages() {
+    let (stream, bootloader) = board().spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    let page: Vec<u8> = (0..PAGE_SIZE).map(|i| i as u8).collect();
+    conn.write_range(0x8000, &page).await.unwrap();
+    assert_eq!(conn.read_range(0x8000, PAGE_SIZE).await.unwrap(), page);
+    assert_eq!(
+        conn.crc32(0x8000, PAGE_SIZE).await.unwrap(),
+        crc32fast::hash(&page)
+    );
+
+    conn.erase_page(0x8000).await.unwrap();
+    assert_eq!(
+        conn.read_range(0x8000, PAGE_SIZE).await.unwrap(),
+        vec![0xFF; PAGE_SIZE]
+    );
+
+    // Every byte of the page has to be escaped
+    let escapes = vec![0xFC; PAGE_SIZE];
+    conn.write_range(0x8000, &escapes).await.unwrap();
+    assert_eq!(conn.read_range(0x8000, PAGE_SIZE).await.unwrap(), escapes);
+
+    conn.write_range(0x8200, &page).await.unwrap();
+    drop(conn);
+    let bootloader = bootloader.await.unwrap().unwrap();
+    assert_eq!(&bootloader.flash()[0x8200..0x8400], page.as_slice());
+}
+
+#[tokio::test]
+async fn reports_bad_addresses() {
+    let (stream, _) = board().spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    let address = FLASH_SIZE as u64;
+    assert!(matches!(
+        conn.erase_page(address).await,
+        Err(TockloaderError::BootloaderBadAddress(bad_address)) if bad_address == address
+    ));
+}
+
+#[tokio::test]
+async fn resends_commands_after_lost_responses() {
+    let (stream, _) = board().with_dropped_responses(2).spawn();
+    let mut conn = connection(stream);
+
+    assert_eq!(conn.read_range(0x40E, 5).await.unwrap(), b"1.1.0");
+}
+
+#[tokio::test]
+async fn resyncs_after_garbled_responses() {
+    let (stream, _) = board().with_garbled_responses(2).spawn();
+    let mut conn = connection(stream);
+
+    assert_eq!(conn.read_range(0x40E, 5).await.unwrap(), b"1.1.0");
+    assert_eq!(conn.read_range(0x40E, 5).await.unwrap(), b"1.1.0");
+}
+
+#[tokio::test]
+async fn gives_up_after_retries() {
+    let (stream, _) = board().with_dropped_responses(2).spawn();
+    let mut conn = connection(stream);
+    conn.set_retries(1);
+    assert!(matches!(
+        conn.read_range(0x40E, 5).await,
+        Err(TockloaderError::BootloaderError(BootloaderError::Timeout(
+            _
+        )))
+    ));
+
+    let (stream, _) = board().with_garbled_responses(1).spawn();
+    let mut conn = connection(stream);
+    conn.set_retries(0);
+    assert!(matches!(
+        conn.read_range(0x40E, 5).await,
+        Err(TockloaderError::BootloaderError(BootloaderError::Garbled(
+            _
+        )))
+    ));
+}
+
+#[tokio::test]
+async fn writes_attributes() {
+    let (stream, _) = board().spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    write_attribute(&mut conn, "board", "other").await.unwrap();
+    write_attribute(&mut conn, "jldevice", "nrf52")
+        .await
+        .unwrap();
+
+    let attributes = read_attributes(&mut conn).await.unwrap();
+    assert_eq!(attributes.get("board"), Some("other"));
+    assert_eq!(attributes.get("jldevice"), Some("nrf52"));
+    assert_eq!(attributes.get("arch"), Some("cortex-m4"));
+}
+
+#[tokio::test]
+async fn installs_and_uninstalls_apps() {
+    let (stream, bootloader) = board().spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    for (name, size) in [("blink", 1024), ("hello", 2048), ("sensors", 512)] {
+        install_app(&mut conn, name, size).await;
+    }
+    assert_eq!(app_names(&mut conn).await, ["blink", "hello", "sensors"]);
+
+    uninstall_app(&mut conn, "hello", false).await.unwrap();
+    assert_eq!(app_names(&mut conn).await, ["blink", "sensors"]);
+
+    drop(conn);
+    let bootloader = bootloader.await.unwrap().unwrap();
+    let flash = bootloader.flash();
+    assert_eq!(
+        &flash[APP_ADDRESS as usize..APP_ADDRESS as usize + 1024],
+        tbf("blink", 1024).as_slice()
+    );
+}
+
+#[tokio::test]
+async fn reads_boards_with_newer_kernel_attributes() {
+    let footer = [0, 0, 0, 2, b'T', b'O', b'C', b'K'];
+    let (stream, _) = board()
+        .with_flash(APP_ADDRESS as usize - footer.len(), &footer)
+        .spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    let attributes = info(&mut conn, &Observer::default()).await.unwrap();
+    assert_eq!(attributes.system.kernel_version, Some(2));
+    assert!(attributes.system.invalid_kernel_attributes.is_some());
+    assert!(attributes.apps.is_empty());
+}
+
+#[tokio::test]
+async fn reads_boards_with_garbled_bootloader_version() {
+    let (stream, _) = board().with_flash(0x40E, b"1.1\xFF\0\0\0\0").spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    let attributes = info(&mut conn, &Observer::default()).await.unwrap();
+    assert_eq!(attributes.system.bootloader_version, None);
+    assert!(matches!(
+        attributes.system.invalid_attributes[..],
+        [TockloaderError::InvalidBootloaderVersion(_)]
+    ));
+    assert_eq!(attributes.system.board.as_deref(), Some("sim"));
+}
+
+#[tokio::test]
+async fn rejects_start_address_before_kernel_attributes() {
+    let (stream, _) = board().with_attribute("appaddr", "0x40").unwrap().spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    assert!(matches!(
+        list(&mut conn, &Observer::default()).await,
+        Err(TockloaderError::MisconfiguredBoard(_))
+    ));
+}
+
+#[tokio::test]
+async fn changes_baud_rate() {
+    let (stream, bootloader) = board().with_baud_rates(&[921600]).spawn();
+    let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+    assert_eq!(conn.change_baud_rate(921600).await.unwrap(), 921600);
+    assert_eq!(conn.read_range(0x40E, 5).await.unwrap(), b"1.1.0");
+
+    drop(conn);
+    assert_eq!(bootloader.await.unwrap().unwrap().baud_rate(), 921600);
+}
+
+#[tokio::test]
+async fn falls_back_to_default_baud_rate() {
+    // A bootloader refusing the baud rate, then one that does not know the command
+    for bootloader in [board().with_baud_rates(&[921600]), board()] {
+        let (stream, bootloader) = bootloader.spawn();
+        let mut conn = SerialConnection::connect(stream).await.unwrap();
+
+        assert_eq!(
+            conn.change_baud_rate(1_000_000).await.unwrap(),
+            DEFAULT_BAUD_RATE
+        );
+        assert_eq!(conn.read_range(0x40E, 5).await.unwrap(), b"1.1.0");
+
+        drop(conn);
+        assert_eq!(
+            bootloader.await.unwrap().unwrap().baud_rate(),
+            DEFAULT_BAUD_RATE
+        );
+    }
+}